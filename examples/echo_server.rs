@@ -29,17 +29,11 @@ pub fn main() -> noargs::Result<()> {
 fn send_response_ok<T: nojson::DisplayJson>(
     socket: &mut std::net::UdpSocket,
     dst: SocketAddr,
-    request_id: &rufton::JsonRpcRequestId,
+    raw_id: &str,
     result: T,
 ) -> std::io::Result<()> {
-    let id = nojson::Json(request_id);
-    let result = nojson::Json(result);
-    let mut buf = Vec::new();
-    write!(
-        &mut buf,
-        r#"{{"jsonrpc":"2.0","id":{id},"result":{result}}}"#,
-    )?;
-    socket.send_to(&buf, dst)?;
+    let response = rufton::JsonRpcResponse::format_result(raw_id, result);
+    socket.send_to(response.as_bytes(), dst)?;
     Ok(())
 }
 
@@ -50,6 +44,9 @@ fn send_response_err(
     code: i32,
     message: &str,
 ) -> std::io::Result<()> {
+    if !rufton::JsonRpcPredefinedError::is_valid_error_code(code) {
+        eprintln!("Warning: error code {code} is in the JSON-RPC reserved range");
+    }
     let id = nojson::Json(request_id);
     let mut buf = Vec::new();
     write!(
@@ -71,14 +68,14 @@ fn run_server(listen_addr: &str) -> Result<(), Box<dyn std::error::Error>> {
         let (len, src_addr) = socket.recv_from(&mut buf)?;
         match rufton::JsonRpcRequest::parse(&buf[..len]) {
             Err(e) => {
-                send_response_err(&mut socket, src_addr, None, e.code(), e.message())?;
+                send_response_err(&mut socket, src_addr, e.id.as_ref(), e.code(), e.message())?;
             }
             Ok(req) => {
-                let Some(req_id) = req.id().cloned() else {
+                // Notifications are not answered; other IDs are echoed verbatim
+                let Some(raw_id) = req.raw_id() else {
                     continue;
                 };
-                let json = req.into_json().into_owned();
-                send_response_ok(&mut socket, src_addr, &req_id, json)?;
+                send_response_ok(&mut socket, src_addr, raw_id, req.json().value())?;
             }
         }
     }
@@ -1,6 +1,42 @@
 pub type Machine = std::collections::HashMap<String, usize>;
 
-pub fn apply(machine: &mut Machine, request: nojson::RawJsonValue) -> rufton::Result<String> {
+/// Error returned to the client as a JSON-RPC error response
+#[derive(Debug)]
+pub struct ErrorResponse {
+    pub code: i32,
+    pub message: String,
+}
+
+impl ErrorResponse {
+    /// The request cannot be served because no leader is known (the client should retry later)
+    pub fn not_leader() -> Self {
+        Self {
+            code: rufton::JsonRpcPredefinedError::server_error(0),
+            message: "not leader".to_owned(),
+        }
+    }
+
+    fn method_not_found(method: &str) -> Self {
+        Self {
+            code: rufton::JsonRpcPredefinedError::MethodNotFound.code(),
+            message: format!("unknown method: {method}"),
+        }
+    }
+}
+
+impl From<nojson::JsonParseError> for ErrorResponse {
+    fn from(e: nojson::JsonParseError) -> Self {
+        Self {
+            code: rufton::JsonRpcPredefinedError::InvalidParams.code(),
+            message: e.to_string(),
+        }
+    }
+}
+
+pub fn apply(
+    machine: &mut Machine,
+    request: nojson::RawJsonValue,
+) -> Result<String, ErrorResponse> {
     let method: &str = request.to_member("method")?.required()?.try_into()?;
     match method {
         "put" => {
@@ -16,24 +52,44 @@ pub fn apply(machine: &mut Machine, request: nojson::RawJsonValue) -> rufton::Re
             let value = machine.get(key);
             Ok(format!(r#"{{ "value":{} }}"#, nojson::Json(value)))
         }
-        _ => Err(format!("unknown method: {}", method).into()),
+        "mget" => {
+            // All keys are read within a single `Apply`, so they observe a single commit point
+            // (`kvs_min` proposes it as a command; `propose_query()` would give the same guarantee)
+            let params = request.to_member("params")?.required()?;
+            let keys: Vec<String> = params.to_member("keys")?.required()?.try_into()?;
+            let values: Vec<_> = keys.iter().map(|key| machine.get(key)).collect();
+            Ok(format!(r#"{{ "values":{} }}"#, nojson::Json(values)))
+        }
+        _ => Err(ErrorResponse::method_not_found(method)),
     }
 }
 
+/// Receives a request, returning `None` if the socket's read timeout expired
 pub fn recv_request<'a>(
     socket: &std::net::UdpSocket,
     buf: &'a mut [u8],
-) -> rufton::Result<(nojson::RawJson<'a>, std::net::SocketAddr)> {
-    let (recv_size, client_addr) = socket.recv_from(buf)?;
+) -> rufton::Result<Option<(nojson::RawJson<'a>, std::net::SocketAddr)>> {
+    let (recv_size, client_addr) = match socket.recv_from(buf) {
+        Ok(x) => x,
+        Err(e)
+            if matches!(
+                e.kind(),
+                std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut
+            ) =>
+        {
+            return Ok(None);
+        }
+        Err(e) => return Err(e.into()),
+    };
     let text = std::str::from_utf8(&buf[..recv_size])?;
     let json = nojson::RawJson::parse(text)?;
-    Ok((json, client_addr))
+    Ok(Some((json, client_addr)))
 }
 
 pub fn send_response(
     socket: &std::net::UdpSocket,
     request: nojson::RawJsonValue<'_, '_>,
-    result: rufton::Result<String>,
+    result: Result<String, ErrorResponse>,
     client_addr: std::net::SocketAddr,
 ) -> rufton::Result<()> {
     // TODO: use optional()
@@ -42,7 +98,10 @@ pub fn send_response(
     };
 
     let result_or_error = match result {
-        Err(e) => format!(r#""error": {{"code": -1, "message":{e}}}"#),
+        Err(e) => {
+            let message = nojson::Json(&e.message);
+            format!(r#""error": {{"code": {}, "message":{message}}}"#, e.code)
+        }
         Ok(v) => format!(r#""result": {v}"#),
     };
     let response = format!(r#"{{"jsonrpc":"2.0", "id":{id}, {result_or_error}}}"#);
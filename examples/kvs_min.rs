@@ -19,22 +19,40 @@ fn run(addr: std::net::SocketAddr) -> rufton::Result<()> {
     let mut node = rufton::Node::start(node_id);
     let members = [NodeId::new(9000), NodeId::new(9001), NodeId::new(9002)];
     if node_id == members[0] {
-        node.init_cluster(&members);
+        node.init_cluster(&members)?;
     }
 
     let mut buf = [0; 65535];
     loop {
-        while let Some(action) = node.next_action() {
+        let tick = node.tick(std::time::Instant::now());
+        for action in tick.actions {
             handle_action(&socket, &node, &mut machine, action)?;
         }
+        for event in tick.events {
+            eprintln!("Event: {}", event);
+        }
 
-        let (json, src_addr) = kvs::recv_request(&socket, &mut buf)?;
+        let timeout = tick
+            .next_deadline
+            .saturating_duration_since(std::time::Instant::now())
+            .max(std::time::Duration::from_millis(1));
+        socket.set_read_timeout(Some(timeout))?;
+        let Some((json, src_addr)) = kvs::recv_request(&socket, &mut buf)? else {
+            continue;
+        };
         let request = json.value();
 
         let method: &str = request.to_member("method")?.required()?.try_into()?;
         if method == "_" {
             let params = request.to_member("params")?.required()?;
             node.handle_message(params);
+        } else if node.leader_id().is_none() {
+            kvs::send_response(
+                &socket,
+                request,
+                Err(kvs::ErrorResponse::not_leader()),
+                src_addr,
+            )?;
         } else {
             node.propose_command(src_addr, request);
         }
@@ -65,9 +83,6 @@ fn handle_action(
                 kvs::send_response(socket, request, result, source.try_into()?)?;
             }
         }
-        rufton::Action::NotifyEvent(event) => {
-            eprintln!("Event: {}", event);
-        }
         _ => {}
     }
     Ok(())
@@ -13,20 +13,25 @@ pub fn main() -> noargs::Result<()> {
     let contact_node = noargs::opt("contact")
         .short('c')
         .take(&mut args)
-        .present_and_then(|a| a.value().parse().map(rufton::NodeId::from_localhost_port))?;
+        .present_and_then(|a| {
+            let port: u16 = a.value().parse().map_err(|e| format!("{e}"))?;
+            rufton::NodeId::from_localhost_port(port).map_err(|e| e.to_string())
+        })?;
 
     if let Some(help) = args.finish()? {
         print!("{help}");
         return Ok(());
     }
 
-    run_node(rufton::NodeId::from_localhost_port(port), contact_node)?;
+    let node_id = rufton::NodeId::from_localhost_port(port)
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e.to_string()))?;
+    run_node(node_id, contact_node)?;
     Ok(())
 }
 
 fn addr(id: rufton::NodeId) -> noargs::Result<SocketAddr> {
     let addr = id
-        .to_localhost_addr()
+        .to_addr(&rufton::LocalhostResolver)
         .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e.to_string()))?;
     Ok(addr)
 }
@@ -51,17 +56,11 @@ fn send_request<T: nojson::DisplayJson>(
 fn send_response<T: nojson::DisplayJson>(
     socket: &UdpSocket,
     dst: SocketAddr,
-    request_id: &rufton::JsonRpcRequestId,
+    raw_id: &str,
     result: T,
 ) -> std::io::Result<()> {
-    let id = nojson::Json(request_id);
-    let result = nojson::Json(result);
-    let mut buf = Vec::new();
-    write!(
-        &mut buf,
-        r#"{{"jsonrpc":"2.0","id":{id},"result":{result}}}"#,
-    )?;
-    socket.send_to(&buf, dst)?;
+    let response = rufton::JsonRpcResponse::format_result(raw_id, result);
+    socket.send_to(response.as_bytes(), dst)?;
     Ok(())
 }
 
@@ -77,35 +76,30 @@ fn run_node(node_id: rufton::NodeId, contact_node: Option<rufton::NodeId>) -> no
     if entries.is_empty() {
         if let Some(contact) = contact_node {
             let members = [node_id, contact];
-            node.init_cluster(&members);
+            node.init_cluster(&members)?;
         } else {
-            node.init_cluster(&[node_id]);
+            node.init_cluster(&[node_id])?;
         }
     } else {
-        let (ok, snapshot) = node.load(&entries);
+        let (ok, snapshot) = node.load_into(&entries)?;
         assert!(ok);
         if let Some(snapshot) = snapshot {
-            machine = snapshot.try_into()?;
+            machine = snapshot;
         }
     }
 
-    let mut timeout_time = next_timeout_time(&node);
     let mut buf = [0u8; 65535];
     loop {
-        let now = std::time::Instant::now();
-        if timeout_time < now {
-            node.handle_timeout();
+        let tick = node.tick(std::time::Instant::now());
+        handle_actions(&socket, &mut storage, &node, &mut machine, tick.actions)?;
+        for event in tick.events {
+            eprintln!("Event: {}", event);
         }
 
-        drain_actions(
-            &socket,
-            &mut storage,
-            &mut node,
-            &mut machine,
-            &mut timeout_time,
-        )?;
-
-        let timeout = timeout_time.saturating_duration_since(now);
+        let timeout = tick
+            .next_deadline
+            .saturating_duration_since(std::time::Instant::now())
+            .max(std::time::Duration::from_millis(1));
         socket.set_read_timeout(Some(timeout))?;
 
         let (len, src_addr) = match socket.recv_from(&mut buf) {
@@ -117,12 +111,14 @@ fn run_node(node_id: rufton::NodeId, contact_node: Option<rufton::NodeId>) -> no
         };
 
         let req = rufton::JsonRpcRequest::parse(&buf[..len]).expect("should return err res");
-        if let Some(req_id) = req.id().cloned() {
+        if req.has_id() {
             assert_eq!(req.method(), "Command");
             let params = req.params().expect("bug");
+            // The ID is kept as raw JSON so that any ID type can be echoed in the response
+            let id = req.json().value().to_member("id")?.required()?;
             let request = nojson::object(|f| {
                 f.member("params", params)?;
-                f.member("id", req_id.clone())
+                f.member("id", id)
             });
             node.propose_command(src_addr, request);
         } else {
@@ -133,22 +129,28 @@ fn run_node(node_id: rufton::NodeId, contact_node: Option<rufton::NodeId>) -> no
     }
 }
 
-fn drain_actions(
+fn handle_actions(
     socket: &UdpSocket,
     storage: &mut rufton::FileStorage,
-    node: &mut rufton::Node,
+    node: &rufton::Node,
     machine: &mut std::collections::HashMap<String, nojson::RawJsonOwned>,
-    timeout_time: &mut std::time::Instant,
+    actions: Vec<rufton::Action>,
 ) -> noargs::Result<()> {
-    while let Some(action) = node.next_action() {
+    // Storage entries are batched and persisted at each SyncStorage barrier
+    let mut pending_entries = Vec::new();
+    for action in actions {
         match action {
-            rufton::Action::AppendStorageEntry(x) => storage.append_entry(&x)?,
+            rufton::Action::AppendStorageEntry(x) => pending_entries.push(x),
+            rufton::Action::SyncStorage => {
+                storage.append_entries(&pending_entries)?;
+                pending_entries.clear();
+            }
             rufton::Action::SendSnapshot(_dst) => {
                 // TODO: take snapshot if node.recent_commits().len() gets too long
                 unreachable!()
             }
             rufton::Action::SetTimeout => {
-                *timeout_time = next_timeout_time(node);
+                // The deadline is available via tick.next_deadline
             }
             rufton::Action::Broadcast(m) => {
                 for dst in node.members() {
@@ -196,29 +198,16 @@ fn drain_actions(
                     _ => rufton::JsonValue::new("unknown type"),
                 };
                 if let Some(source) = apply.source() {
-                    let req_id: rufton::JsonRpcRequestId =
-                        request_value.to_member("id")?.required()?.try_into()?;
+                    let raw_id = request_value.to_member("id")?.required()?.as_raw_str();
                     let src: SocketAddr = source.try_into()?;
-                    send_response(socket, src, &req_id, result)?;
+                    send_response(socket, src, raw_id, result)?;
                 }
             }
-            rufton::Action::NotifyEvent(event) => {
-                eprintln!("Event: {}", event);
+            rufton::Action::NotifyEvent(_) => {
+                // Events are delivered via tick.events
             }
         }
     }
+    storage.append_entries(&pending_entries)?;
     Ok(())
 }
-
-fn next_timeout_time(node: &rufton::Node) -> std::time::Instant {
-    let ms = if node.is_leader() {
-        50
-    } else if node.is_follower() {
-        150
-    } else if node.is_candidate() {
-        150 + rand::random::<u64>() % 50
-    } else {
-        unreachable!()
-    };
-    std::time::Instant::now() + std::time::Duration::from_millis(ms)
-}
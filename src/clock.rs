@@ -0,0 +1,47 @@
+/// Source of the current time used by `Node` to compute timeout deadlines
+pub trait Clock: std::fmt::Debug + Send + Sync {
+    fn now(&self) -> std::time::Instant;
+}
+
+/// `Clock` implementation based on `std::time::Instant::now()`
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> std::time::Instant {
+        std::time::Instant::now()
+    }
+}
+
+/// `Clock` implementation whose time only advances when explicitly requested (for testing)
+///
+/// Clones share the same underlying time.
+#[derive(Debug, Clone)]
+pub struct ManualClock {
+    now: std::sync::Arc<std::sync::Mutex<std::time::Instant>>,
+}
+
+impl ManualClock {
+    pub fn new(now: std::time::Instant) -> Self {
+        Self {
+            now: std::sync::Arc::new(std::sync::Mutex::new(now)),
+        }
+    }
+
+    pub fn advance(&self, duration: std::time::Duration) {
+        let mut now = self.now.lock().unwrap_or_else(|e| e.into_inner());
+        *now += duration;
+    }
+}
+
+impl Default for ManualClock {
+    fn default() -> Self {
+        Self::new(std::time::Instant::now())
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> std::time::Instant {
+        *self.now.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
@@ -123,6 +123,91 @@ pub fn fmt_message(
     })
 }
 
+pub fn message_to_json(
+    message: &noraft::Message,
+    commands: &crate::node::RecentCommands,
+) -> crate::node::JsonValue {
+    crate::node::JsonValue::new(nojson::json(|f| fmt_message(f, message, commands)))
+}
+
+/// Same as `message_to_json()`, but drops trailing entries of an `AppendEntriesCall` so that
+/// the message is accepted by `json_to_message_with_limits()`
+///
+/// The dropped entries are sent later, once the follower reports the position it has reached.
+/// A single entry exceeding `max_bytes` is sent as is, since it cannot be split; the node
+/// prevents this by rejecting commands that do not satisfy `command_fits_limits()`.
+pub fn message_to_json_with_limits(
+    message: &noraft::Message,
+    commands: &crate::node::RecentCommands,
+    limits: MessageLimits,
+) -> crate::node::JsonValue {
+    let noraft::Message::AppendEntriesCall {
+        from,
+        term,
+        commit_index,
+        entries,
+    } = message
+    else {
+        return message_to_json(message, commands);
+    };
+
+    let total = entries.iter_with_positions().count();
+    let mut count = total.min(limits.max_entries);
+    loop {
+        let value = if count == total {
+            message_to_json(message, commands)
+        } else {
+            let mut prefix = noraft::LogEntries::new(entries.prev_position());
+            for (_, entry) in entries.iter_with_positions().take(count) {
+                prefix.push(entry);
+            }
+            // The follower must not commit entries it has not received yet
+            let last_index = prefix.last_position().index;
+            let truncated = noraft::Message::AppendEntriesCall {
+                from: *from,
+                term: *term,
+                commit_index: (*commit_index).min(last_index),
+                entries: prefix,
+            };
+            message_to_json(&truncated, commands)
+        };
+        if value.byte_len() <= limits.max_bytes || count <= 1 {
+            return value;
+        }
+        count /= 2;
+    }
+}
+
+/// Returns `true` if an `AppendEntriesCall` carrying only `command` is within `limits.max_bytes`
+///
+/// The message is sized with the largest possible node ID, term and indices,
+/// so a command accepted here fits whenever it is replicated.
+pub fn command_fits_limits(command: &crate::node::JsonValue, limits: MessageLimits) -> bool {
+    if limits.max_bytes == usize::MAX {
+        return true;
+    }
+
+    let max = u64::MAX;
+    let mut entries = noraft::LogEntries::new(noraft::LogPosition {
+        term: noraft::Term::new(max),
+        index: noraft::LogIndex::new(max - 1),
+    });
+    entries.push(noraft::LogEntry::Command);
+    let message = noraft::Message::AppendEntriesCall {
+        from: noraft::NodeId::new(max),
+        term: noraft::Term::new(max),
+        commit_index: noraft::LogIndex::new(max),
+        entries,
+    };
+
+    // The command is embedded verbatim, so a placeholder avoids copying it
+    let placeholder = crate::node::JsonValue::new(0);
+    let mut commands = crate::node::RecentCommands::new();
+    commands.insert(noraft::LogIndex::new(max), placeholder.clone());
+    let overhead = message_to_json(&message, &commands).byte_len() - placeholder.byte_len();
+    overhead.saturating_add(command.byte_len()) <= limits.max_bytes
+}
+
 fn fmt_message_common_members(
     f: &mut nojson::JsonObjectFormatter<'_, '_, '_>,
     from: noraft::NodeId,
@@ -212,6 +297,44 @@ pub fn get_command_values(
     )
 }
 
+/// Upper bounds on the size of messages accepted by `json_to_message_with_limits()`
+///
+/// The limits are checked after the JSON text has been received and parsed, so they do not bound
+/// the memory used by the transport. They bound the work of decoding a message and the number of
+/// entries a node appends at once.
+///
+/// The leader also splits `AppendEntriesCall` messages to fit its own limits
+/// (see `message_to_json_with_limits()`), so every node should use the same limits.
+/// The default is unlimited.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MessageLimits {
+    /// Maximum number of log entries in an `AppendEntriesCall`
+    pub max_entries: usize,
+    /// Maximum length of the JSON text of a message
+    pub max_bytes: usize,
+}
+
+impl MessageLimits {
+    pub const UNLIMITED: Self = Self {
+        max_entries: usize::MAX,
+        max_bytes: usize::MAX,
+    };
+
+    /// Returns an error if no `AppendEntriesCall` could carry an entry under these limits
+    pub fn validate(&self) -> crate::Result<()> {
+        if self.max_entries == 0 {
+            return Err(crate::Error::new("max_entries must be at least 1"));
+        }
+        Ok(())
+    }
+}
+
+impl Default for MessageLimits {
+    fn default() -> Self {
+        Self::UNLIMITED
+    }
+}
+
 /// Converts a JSON value to a Message, excluding the command value
 ///
 /// This function parses JSON representations of Raft messages back into their
@@ -220,6 +343,24 @@ pub fn get_command_values(
 pub fn json_to_message(
     value: nojson::RawJsonValue<'_, '_>,
 ) -> Result<noraft::Message, nojson::JsonParseError> {
+    json_to_message_with_limits(value, MessageLimits::UNLIMITED)
+}
+
+/// Same as `json_to_message()`, but rejects messages exceeding `limits`
+///
+/// The limits are checked before any log entry is decoded.
+pub fn json_to_message_with_limits(
+    value: nojson::RawJsonValue<'_, '_>,
+    limits: MessageLimits,
+) -> Result<noraft::Message, nojson::JsonParseError> {
+    let bytes = value.as_raw_str().len();
+    if bytes > limits.max_bytes {
+        return Err(value.invalid(format!(
+            "message too large: {bytes} bytes (limit: {})",
+            limits.max_bytes
+        )));
+    }
+
     let msg_type = value.to_member("type")?.required()?.as_string_str()?;
 
     let from = noraft::NodeId::new(value.to_member("from")?.required()?.try_into()?);
@@ -263,8 +404,16 @@ pub fn json_to_message(
                 index: prev_index,
             };
 
-            let entries_array = value.to_member("entries")?.required()?.to_array()?;
+            let entries_value = value.to_member("entries")?.required()?;
+            let count = entries_value.to_array()?.count();
+            if count > limits.max_entries {
+                return Err(entries_value.invalid(format!(
+                    "too many log entries: {count} (limit: {})",
+                    limits.max_entries
+                )));
+            }
 
+            let entries_array = entries_value.to_array()?;
             let mut entries = noraft::LogEntries::new(prev_position);
             for entry_value in entries_array {
                 let entry = parse_log_entry(entry_value)?;
@@ -298,3 +447,206 @@ pub fn json_to_message(
         _ => Err(value.invalid(format!("Unknown message type: {msg_type}"))),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::node::{JsonValue, RecentCommands};
+
+    fn node_id(n: u64) -> noraft::NodeId {
+        noraft::NodeId::new(n)
+    }
+
+    fn position(term: u64, index: u64) -> noraft::LogPosition {
+        noraft::LogPosition {
+            term: noraft::Term::new(term),
+            index: noraft::LogIndex::new(index),
+        }
+    }
+
+    fn assert_round_trip(message: noraft::Message, commands: &RecentCommands) {
+        let json = message_to_json(&message, commands);
+        let decoded = json_to_message(json.get()).expect("message should be decodable");
+        assert_eq!(decoded, message);
+
+        let decoded_commands: RecentCommands = get_command_values(json.get(), &decoded)
+            .into_iter()
+            .flatten()
+            .map(|(pos, value)| (pos.index, value))
+            .collect();
+        assert_eq!(&decoded_commands, commands);
+    }
+
+    #[test]
+    fn request_vote_round_trip() {
+        let commands = RecentCommands::new();
+        assert_round_trip(
+            noraft::Message::RequestVoteCall {
+                from: node_id(1),
+                term: noraft::Term::new(3),
+                last_position: position(2, 10),
+            },
+            &commands,
+        );
+        assert_round_trip(
+            noraft::Message::RequestVoteReply {
+                from: node_id(2),
+                term: noraft::Term::new(3),
+                vote_granted: true,
+            },
+            &commands,
+        );
+    }
+
+    #[test]
+    fn oversized_append_entries_is_rejected() {
+        let mut entries = noraft::LogEntries::new(position(1, 0));
+        for _ in 0..10 {
+            entries.push(noraft::LogEntry::Term(noraft::Term::new(1)));
+        }
+        let message = noraft::Message::AppendEntriesCall {
+            from: node_id(1),
+            term: noraft::Term::new(1),
+            commit_index: noraft::LogIndex::new(0),
+            entries,
+        };
+        let json = message_to_json(&message, &RecentCommands::new());
+
+        let limits = MessageLimits {
+            max_entries: 9,
+            ..MessageLimits::default()
+        };
+        let err = json_to_message_with_limits(json.get(), limits).expect_err("too many entries");
+        assert!(err.to_string().contains("too many log entries"), "{err}");
+
+        let limits = MessageLimits {
+            max_bytes: json.byte_len() - 1,
+            ..MessageLimits::default()
+        };
+        let err = json_to_message_with_limits(json.get(), limits).expect_err("too large");
+        assert!(err.to_string().contains("message too large"), "{err}");
+
+        let limits = MessageLimits {
+            max_entries: 10,
+            max_bytes: json.byte_len(),
+        };
+        assert_eq!(
+            json_to_message_with_limits(json.get(), limits).expect("within limits"),
+            message
+        );
+    }
+
+    #[test]
+    fn append_entries_is_split_to_fit_limits() {
+        let mut entries = noraft::LogEntries::new(position(1, 0));
+        for _ in 0..10 {
+            entries.push(noraft::LogEntry::Term(noraft::Term::new(1)));
+        }
+        let message = noraft::Message::AppendEntriesCall {
+            from: node_id(1),
+            term: noraft::Term::new(1),
+            commit_index: noraft::LogIndex::new(8),
+            entries,
+        };
+        let commands = RecentCommands::new();
+        let full = message_to_json(&message, &commands);
+
+        let limits = MessageLimits {
+            max_entries: 4,
+            max_bytes: full.byte_len() / 2,
+        };
+        let json = message_to_json_with_limits(&message, &commands, limits);
+        let noraft::Message::AppendEntriesCall {
+            commit_index,
+            entries,
+            ..
+        } = json_to_message_with_limits(json.get(), limits).expect("within limits")
+        else {
+            panic!("AppendEntriesCall expected");
+        };
+        let count = entries.iter_with_positions().count();
+        assert!((1..=4).contains(&count), "{count}");
+        assert_eq!(entries.prev_position(), position(1, 0));
+        assert_eq!(commit_index, entries.last_position().index);
+
+        // Messages within the limits are left unchanged
+        let json = message_to_json_with_limits(&message, &commands, MessageLimits::UNLIMITED);
+        assert_eq!(json, full);
+    }
+
+    #[test]
+    fn command_fits_limits_bounds_replicated_message() {
+        let command = JsonValue::new("x".repeat(100));
+        assert!(command_fits_limits(&command, MessageLimits::UNLIMITED));
+
+        // The worst-case message carrying the command
+        let mut entries = noraft::LogEntries::new(position(u64::MAX, u64::MAX - 1));
+        entries.push(noraft::LogEntry::Command);
+        let message = noraft::Message::AppendEntriesCall {
+            from: node_id(u64::MAX),
+            term: noraft::Term::new(u64::MAX),
+            commit_index: noraft::LogIndex::new(u64::MAX),
+            entries,
+        };
+        let mut commands = RecentCommands::new();
+        commands.insert(noraft::LogIndex::new(u64::MAX), command.clone());
+        let max_bytes = message_to_json(&message, &commands).byte_len();
+
+        let limits = |max_bytes| MessageLimits {
+            max_bytes,
+            ..MessageLimits::default()
+        };
+        assert!(command_fits_limits(&command, limits(max_bytes)));
+        assert!(!command_fits_limits(&command, limits(max_bytes - 1)));
+    }
+
+    #[test]
+    fn zero_max_entries_is_invalid() {
+        let limits = MessageLimits {
+            max_entries: 0,
+            ..MessageLimits::default()
+        };
+        assert!(limits.validate().is_err());
+        assert!(MessageLimits::UNLIMITED.validate().is_ok());
+    }
+
+    #[test]
+    fn append_entries_round_trip() {
+        let mut config = noraft::ClusterConfig::new();
+        config.voters.insert(node_id(1));
+        config.voters.insert(node_id(2));
+        config.new_voters.insert(node_id(3));
+
+        let mut entries = noraft::LogEntries::new(position(1, 4));
+        entries.push(noraft::LogEntry::Term(noraft::Term::new(2)));
+        entries.push(noraft::LogEntry::ClusterConfig(config));
+        entries.push(noraft::LogEntry::Command);
+        entries.push(noraft::LogEntry::Command);
+
+        let mut commands = RecentCommands::new();
+        commands.insert(noraft::LogIndex::new(7), JsonValue::new("first"));
+        commands.insert(
+            noraft::LogIndex::new(8),
+            JsonValue::new(nojson::object(|f| f.member("key", [1, 2, 3]))),
+        );
+
+        assert_round_trip(
+            noraft::Message::AppendEntriesCall {
+                from: node_id(1),
+                term: noraft::Term::new(2),
+                commit_index: noraft::LogIndex::new(5),
+                entries,
+            },
+            &commands,
+        );
+        assert_round_trip(
+            noraft::Message::AppendEntriesReply {
+                from: node_id(2),
+                term: noraft::Term::new(2),
+                generation: noraft::NodeGeneration::new(4),
+                last_position: position(2, 8),
+            },
+            &RecentCommands::new(),
+        );
+    }
+}
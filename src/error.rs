@@ -2,6 +2,7 @@
 pub struct Error {
     pub reason: String,
     pub backtrace: std::backtrace::Backtrace,
+    source: Option<Box<dyn std::error::Error + Send + Sync + 'static>>,
 }
 
 impl Error {
@@ -9,6 +10,19 @@ impl Error {
         Self {
             reason: reason.into(),
             backtrace: std::backtrace::Backtrace::capture(),
+            source: None,
+        }
+    }
+
+    /// Makes an error that retains `source` as the underlying cause (see `std::error::Error::source()`)
+    pub fn with_source<E>(source: E) -> Self
+    where
+        E: std::error::Error + Send + Sync + 'static,
+    {
+        Self {
+            reason: source.to_string(),
+            backtrace: std::backtrace::Backtrace::capture(),
+            source: Some(Box::new(source)),
         }
     }
 }
@@ -25,35 +39,41 @@ impl std::fmt::Display for Error {
     }
 }
 
-impl std::error::Error for Error {}
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        self.source
+            .as_deref()
+            .map(|e| e as &(dyn std::error::Error + 'static))
+    }
+}
 
 impl From<std::num::ParseIntError> for Error {
     fn from(err: std::num::ParseIntError) -> Self {
-        Self::new(err.to_string())
+        Self::with_source(err)
     }
 }
 
 impl From<std::io::Error> for Error {
     fn from(err: std::io::Error) -> Self {
-        Self::new(err.to_string())
+        Self::with_source(err)
     }
 }
 
 impl From<std::net::AddrParseError> for Error {
     fn from(err: std::net::AddrParseError) -> Self {
-        Self::new(err.to_string())
+        Self::with_source(err)
     }
 }
 
 impl From<std::str::Utf8Error> for Error {
     fn from(err: std::str::Utf8Error) -> Self {
-        Self::new(err.to_string())
+        Self::with_source(err)
     }
 }
 
 impl From<nojson::JsonParseError> for Error {
     fn from(err: nojson::JsonParseError) -> Self {
-        Self::new(err.to_string())
+        Self::with_source(err)
     }
 }
 
@@ -62,3 +82,99 @@ impl From<String> for Error {
         Self::new(err)
     }
 }
+
+/// Error returned when an internal JSON message or storage entry cannot be decoded
+#[derive(Debug)]
+pub struct DecodeError {
+    /// Name of the type being decoded (e.g., `"Command"`)
+    pub type_name: &'static str,
+
+    /// Name of the offending member, or `None` if the error is not specific to a member
+    pub field: Option<&'static str>,
+
+    pub source: nojson::JsonParseError,
+}
+
+impl DecodeError {
+    pub(crate) fn new(
+        type_name: &'static str,
+        field: Option<&'static str>,
+        source: nojson::JsonParseError,
+    ) -> Self {
+        Self {
+            type_name,
+            field,
+            source,
+        }
+    }
+}
+
+impl std::fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.field {
+            Some(field) => write!(f, "failed to decode {}.{field}: ", self.type_name)?,
+            None => write!(f, "failed to decode {}: ", self.type_name)?,
+        }
+        write!(f, "{}", self.source)
+    }
+}
+
+impl std::error::Error for DecodeError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.source)
+    }
+}
+
+impl From<DecodeError> for Error {
+    fn from(err: DecodeError) -> Self {
+        Self::with_source(err)
+    }
+}
+
+/// Error returned by `Node::init_cluster()`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InitError {
+    /// The node has already been initialized (by `init_cluster()`, `load()` or by joining a cluster)
+    AlreadyInitialized,
+
+    /// The given members list does not contain the node itself
+    NotAMember,
+}
+
+impl std::fmt::Display for InitError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::AlreadyInitialized => write!(f, "node is already initialized"),
+            Self::NotAMember => write!(f, "members list does not contain the node itself"),
+        }
+    }
+}
+
+impl std::error::Error for InitError {}
+
+impl From<InitError> for Error {
+    fn from(err: InitError) -> Self {
+        Self::with_source(err)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn source_retains_io_error_kind() {
+        let io_error = std::io::Error::new(std::io::ErrorKind::NotFound, "no such file");
+        let error = Error::from(io_error);
+        assert_eq!(error.reason, "no such file");
+
+        let source = std::error::Error::source(&error).expect("source should be retained");
+        let io_error = source
+            .downcast_ref::<std::io::Error>()
+            .expect("source should be an io::Error");
+        assert_eq!(io_error.kind(), std::io::ErrorKind::NotFound);
+
+        let error = Error::new("plain reason");
+        assert!(std::error::Error::source(&error).is_none());
+    }
+}
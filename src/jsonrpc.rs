@@ -41,6 +41,51 @@ pub enum JsonRpcPredefinedError {
 }
 
 impl JsonRpcPredefinedError {
+    /// Range of error codes reserved by the JSON-RPC 2.0 specification
+    pub const RESERVED_CODES: std::ops::RangeInclusive<i32> = -32768..=-32000;
+
+    /// Range of error codes reserved for implementation-defined server errors
+    pub const SERVER_ERROR_CODES: std::ops::RangeInclusive<i32> = -32099..=-32000;
+
+    /// Returns the server error code at `offset` within `SERVER_ERROR_CODES` (`-32000 - offset`)
+    ///
+    /// # Panics
+    ///
+    /// Panics if `offset` is greater than 99.
+    pub fn server_error(offset: u8) -> i32 {
+        assert!(offset < 100, "server error offset out of range: {offset}");
+        -32000 - i32::from(offset)
+    }
+
+    /// Inverse of `server_error()`: returns the offset of `code` if it is a server error code
+    pub fn server_error_offset(code: i32) -> Option<u8> {
+        Self::SERVER_ERROR_CODES
+            .contains(&code)
+            .then(|| (-32000 - code) as u8)
+    }
+
+    pub fn from_code(code: i32) -> Option<Self> {
+        match code {
+            -32700 => Some(Self::ParseError),
+            -32600 => Some(Self::InvalidRequest),
+            -32601 => Some(Self::MethodNotFound),
+            -32602 => Some(Self::InvalidParams),
+            -32603 => Some(Self::InternalError),
+            _ => None,
+        }
+    }
+
+    /// Returns `true` if `code` can be used in an error response
+    ///
+    /// Application-defined codes must be outside `RESERVED_CODES`;
+    /// codes within the range are only valid if they are predefined ones
+    /// or server error codes (see `SERVER_ERROR_CODES`).
+    pub fn is_valid_error_code(code: i32) -> bool {
+        !Self::RESERVED_CODES.contains(&code)
+            || Self::from_code(code).is_some()
+            || Self::SERVER_ERROR_CODES.contains(&code)
+    }
+
     pub fn code(self) -> i32 {
         match self {
             Self::ParseError => -32700,
@@ -62,6 +107,26 @@ impl JsonRpcPredefinedError {
     }
 }
 
+/// Error returned by `JsonRpcRequest::parse()`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct JsonRpcRequestError {
+    /// ID of the request, if it could be extracted from the malformed request
+    ///
+    /// Error responses should echo this ID (or `null` if `None`) as required by the spec.
+    pub id: Option<JsonRpcRequestId>,
+    pub error: JsonRpcPredefinedError,
+}
+
+impl JsonRpcRequestError {
+    pub fn code(&self) -> i32 {
+        self.error.code()
+    }
+
+    pub fn message(&self) -> &'static str {
+        self.error.message()
+    }
+}
+
 fn is_jsonrpc_2_0<'text, 'raw>(val: nojson::RawJsonValue<'text, 'raw>) -> bool {
     match val.to_unquoted_string_str() {
         Ok(version) => version == "2.0",
@@ -84,30 +149,67 @@ pub struct JsonRpcRequest<'text> {
     method: std::borrow::Cow<'text, str>,
     params_index: Option<usize>,
     id: Option<JsonRpcRequestId>,
+    id_index: Option<usize>,
 }
 
 impl<'text> JsonRpcRequest<'text> {
-    pub fn parse(line: &'text [u8]) -> Result<Self, JsonRpcPredefinedError> {
+    pub fn parse(line: &'text [u8]) -> Result<Self, JsonRpcRequestError> {
         let json = std::str::from_utf8(line)
             .ok()
             .and_then(|line| nojson::RawJson::parse(line).ok())
-            .ok_or(JsonRpcPredefinedError::ParseError)?;
-        Self::from_json(json).ok_or(JsonRpcPredefinedError::InvalidRequest)
+            .ok_or(JsonRpcRequestError {
+                id: None,
+                error: JsonRpcPredefinedError::ParseError,
+            })?;
+        Self::from_json(json).map_err(|id| JsonRpcRequestError {
+            id,
+            error: JsonRpcPredefinedError::InvalidRequest,
+        })
     }
 
     pub fn method(&self) -> &str {
         self.method.as_ref()
     }
 
+    /// Returns the request ID
+    ///
+    /// Returns `None` for notifications and for IDs that are neither integers nor strings
+    /// (see `raw_id()`).
     pub fn id(&self) -> Option<&JsonRpcRequestId> {
         self.id.as_ref()
     }
 
+    /// Returns the JSON text of the request ID regardless of its type
+    ///
+    /// This allows echoing IDs that `JsonRpcRequestId` cannot model (e.g., `1.5` or `null`)
+    /// verbatim in the response.
+    pub fn raw_id(&self) -> Option<&str> {
+        self.id_index
+            .and_then(|i| self.json.get_value_by_index(i))
+            .map(|id| id.as_raw_str())
+    }
+
+    /// Returns `true` unless this request is a notification (i.e., it must be answered)
+    ///
+    /// Unlike `id().is_some()`, this is also `true` for IDs that `JsonRpcRequestId` cannot model.
+    pub fn has_id(&self) -> bool {
+        self.id_index.is_some()
+    }
+
     pub fn params(&self) -> Option<nojson::RawJsonValue<'text, '_>> {
         self.params_index
             .and_then(|i| self.json.get_value_by_index(i))
     }
 
+    /// Converts the params into `T`, returning `InvalidParams` if they are missing or malformed
+    pub fn params_as<'a, T>(&'a self) -> Result<T, JsonRpcPredefinedError>
+    where
+        T: TryFrom<nojson::RawJsonValue<'text, 'a>, Error = nojson::JsonParseError>,
+    {
+        let params = self.params().ok_or(JsonRpcPredefinedError::InvalidParams)?;
+        T::try_from(params).map_err(|_| JsonRpcPredefinedError::InvalidParams)
+    }
+
     pub fn json(&self) -> &nojson::RawJson<'text> {
         &self.json
     }
@@ -116,14 +218,72 @@ impl<'text> JsonRpcRequest<'text> {
         self.json
     }
 
-    fn from_json(json: nojson::RawJson<'text>) -> Option<Self> {
-        let mut parts = RequestParts::new();
-        let value = json.value();
-        for (key, val) in value.to_object().ok()? {
-            let key = key.to_unquoted_string_str().ok()?;
-            parts.apply_member(key.as_ref(), val)?;
+    /// Converts this request into one that does not borrow the input buffer
+    ///
+    /// This is useful to queue requests beyond the lifetime of the receive buffer.
+    pub fn into_owned(self) -> OwnedJsonRpcRequest {
+        OwnedJsonRpcRequest {
+            json: self.json.into_owned(),
+            method: self.method.into_owned(),
+            params_index: self.params_index,
+            id: self.id,
+            id_index: self.id_index,
         }
-        parts.finish(json)
+    }
+
+    /// Returns the request ID (if extractable) on failure
+    fn from_json(json: nojson::RawJson<'text>) -> Result<Self, Option<JsonRpcRequestId>> {
+        let Some(parts) = RequestParts::collect(json.value()) else {
+            return Err(recover_id(json.value()));
+        };
+        let id = parts.id.clone();
+        parts.finish(json).ok_or(id)
+    }
+}
+
+fn recover_id<'text, 'raw>(value: nojson::RawJsonValue<'text, 'raw>) -> Option<JsonRpcRequestId> {
+    let id = value.to_member("id").ok()?.get()?;
+    JsonRpcRequestId::try_from(id).ok()
+}
+
+/// Owned version of `JsonRpcRequest` created by `JsonRpcRequest::into_owned()`
+#[derive(Debug)]
+pub struct OwnedJsonRpcRequest {
+    json: nojson::RawJsonOwned,
+    method: String,
+    params_index: Option<usize>,
+    id: Option<JsonRpcRequestId>,
+    id_index: Option<usize>,
+}
+
+impl OwnedJsonRpcRequest {
+    pub fn method(&self) -> &str {
+        &self.method
+    }
+
+    pub fn id(&self) -> Option<&JsonRpcRequestId> {
+        self.id.as_ref()
+    }
+
+    /// See `JsonRpcRequest::raw_id()`
+    pub fn raw_id(&self) -> Option<&str> {
+        self.id_index
+            .and_then(|i| self.json.get_value_by_index(i))
+            .map(|id| id.as_raw_str())
+    }
+
+    /// See `JsonRpcRequest::has_id()`
+    pub fn has_id(&self) -> bool {
+        self.id_index.is_some()
+    }
+
+    pub fn params(&self) -> Option<nojson::RawJsonValue<'_, '_>> {
+        self.params_index
+            .and_then(|i| self.json.get_value_by_index(i))
+    }
+
+    pub fn json(&self) -> &nojson::RawJsonOwned {
+        &self.json
     }
 }
 
@@ -131,6 +291,7 @@ struct RequestParts<'text> {
     has_jsonrpc: bool,
     method: Option<std::borrow::Cow<'text, str>>,
     id: Option<JsonRpcRequestId>,
+    id_index: Option<usize>,
     params_index: Option<usize>,
 }
 
@@ -140,10 +301,20 @@ impl<'text> RequestParts<'text> {
             has_jsonrpc: false,
             method: None,
             id: None,
+            id_index: None,
             params_index: None,
         }
     }
 
+    fn collect<'raw>(value: nojson::RawJsonValue<'text, 'raw>) -> Option<Self> {
+        let mut parts = Self::new();
+        for (key, val) in value.to_object().ok()? {
+            let key = key.to_unquoted_string_str().ok()?;
+            parts.apply_member(key.as_ref(), val)?;
+        }
+        Some(parts)
+    }
+
     fn apply_member<'raw>(
         &mut self,
         key: &str,
@@ -160,7 +331,9 @@ impl<'text> RequestParts<'text> {
                 self.method = Some(val.to_unquoted_string_str().ok()?);
             }
             "id" => {
-                self.id = Some(JsonRpcRequestId::try_from(val).ok()?);
+                // IDs of other types are kept only as raw JSON (see `JsonRpcRequest::raw_id()`)
+                self.id = JsonRpcRequestId::try_from(val).ok();
+                self.id_index = Some(val.index());
             }
             "params" => {
                 if !matches!(
@@ -185,6 +358,7 @@ impl<'text> RequestParts<'text> {
             method: self.method?,
             params_index: self.params_index,
             id: self.id,
+            id_index: self.id_index,
         })
     }
 }
@@ -232,6 +406,17 @@ impl<'text> JsonRpcResponse<'text> {
     }
 }
 
+impl JsonRpcResponse<'_> {
+    /// Formats a success response to the request whose ID is `raw_id`
+    ///
+    /// `raw_id` is the JSON text of the request ID (see `JsonRpcRequest::raw_id()`) and is echoed
+    /// verbatim, so that IDs that `JsonRpcRequestId` cannot model are preserved.
+    pub fn format_result<T: nojson::DisplayJson>(raw_id: &str, result: T) -> String {
+        let result = nojson::Json(result);
+        format!(r#"{{"jsonrpc":"2.0","id":{raw_id},"result":{result}}}"#)
+    }
+}
+
 struct ResponseParts {
     has_jsonrpc: bool,
     has_id: bool,
@@ -309,3 +494,184 @@ impl ResponseParts {
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, PartialEq)]
+    struct PutParams {
+        key: String,
+        value: u64,
+    }
+
+    impl<'text, 'raw> TryFrom<nojson::RawJsonValue<'text, 'raw>> for PutParams {
+        type Error = nojson::JsonParseError;
+
+        fn try_from(value: nojson::RawJsonValue<'text, 'raw>) -> Result<Self, Self::Error> {
+            Ok(Self {
+                key: value.to_member("key")?.required()?.try_into()?,
+                value: value.to_member("value")?.required()?.try_into()?,
+            })
+        }
+    }
+
+    #[test]
+    fn params_as_valid_params() {
+        let line = br#"{"jsonrpc":"2.0","method":"put","params":{"key":"a","value":1},"id":1}"#;
+        let req = JsonRpcRequest::parse(line).expect("valid request");
+        let params: PutParams = req.params_as().expect("valid params");
+        assert_eq!(
+            params,
+            PutParams {
+                key: "a".to_owned(),
+                value: 1
+            }
+        );
+    }
+
+    #[test]
+    fn params_as_invalid_params() {
+        let lines: [&[u8]; 3] = [
+            br#"{"jsonrpc":"2.0","method":"put","params":{"key":"a"},"id":1}"#,
+            br#"{"jsonrpc":"2.0","method":"put","params":{"key":1,"value":1},"id":1}"#,
+            br#"{"jsonrpc":"2.0","method":"put","id":1}"#,
+        ];
+        for line in lines {
+            let req = JsonRpcRequest::parse(line).expect("valid request");
+            let err = req.params_as::<PutParams>().expect_err("invalid params");
+            assert_eq!(err, JsonRpcPredefinedError::InvalidParams);
+        }
+    }
+
+    #[test]
+    fn error_code_validation() {
+        for error in [
+            JsonRpcPredefinedError::ParseError,
+            JsonRpcPredefinedError::InvalidRequest,
+            JsonRpcPredefinedError::MethodNotFound,
+            JsonRpcPredefinedError::InvalidParams,
+            JsonRpcPredefinedError::InternalError,
+        ] {
+            assert_eq!(JsonRpcPredefinedError::from_code(error.code()), Some(error));
+            assert!(JsonRpcPredefinedError::is_valid_error_code(error.code()));
+        }
+
+        assert_eq!(JsonRpcPredefinedError::from_code(-32001), None);
+        assert!(JsonRpcPredefinedError::is_valid_error_code(-32001));
+        assert!(!JsonRpcPredefinedError::is_valid_error_code(-32100));
+        assert!(!JsonRpcPredefinedError::is_valid_error_code(-32768));
+        assert!(JsonRpcPredefinedError::is_valid_error_code(-31999));
+        assert!(JsonRpcPredefinedError::is_valid_error_code(-1));
+    }
+
+    #[test]
+    fn server_error_codes() {
+        for offset in [0, 1, 50, 99] {
+            let code = JsonRpcPredefinedError::server_error(offset);
+            assert!(JsonRpcPredefinedError::SERVER_ERROR_CODES.contains(&code));
+            assert!(JsonRpcPredefinedError::RESERVED_CODES.contains(&code));
+            assert_eq!(
+                JsonRpcPredefinedError::server_error_offset(code),
+                Some(offset)
+            );
+        }
+        assert_eq!(JsonRpcPredefinedError::server_error(0), -32000);
+        assert_eq!(JsonRpcPredefinedError::server_error(99), -32099);
+
+        assert_eq!(JsonRpcPredefinedError::server_error_offset(-31999), None);
+        assert_eq!(JsonRpcPredefinedError::server_error_offset(-32100), None);
+        assert_eq!(JsonRpcPredefinedError::server_error_offset(-32603), None);
+    }
+
+    #[test]
+    #[should_panic(expected = "server error offset out of range")]
+    fn server_error_offset_out_of_range() {
+        JsonRpcPredefinedError::server_error(100);
+    }
+
+    #[test]
+    fn owned_request_outlives_buffer() {
+        let mut buf =
+            br#"{"jsonrpc":"2.0","method":"put","params":{"key":"a","value":1},"id":1}"#.to_vec();
+        let mut queue = Vec::new();
+        queue.push(
+            JsonRpcRequest::parse(&buf)
+                .expect("valid request")
+                .into_owned(),
+        );
+
+        // Reuse the buffer for the next request
+        buf.fill(b' ');
+        buf[..2].copy_from_slice(b"{}");
+        assert!(JsonRpcRequest::parse(&buf).is_err());
+
+        let req = queue.pop().expect("queued request");
+        assert_eq!(req.method(), "put");
+        assert_eq!(req.id(), Some(&JsonRpcRequestId::Integer(1)));
+        let params = req.params().expect("params");
+        assert_eq!(params.as_raw_str(), r#"{"key":"a","value":1}"#);
+    }
+
+    #[test]
+    fn parse_request_with_non_standard_id() {
+        let line = br#"{"jsonrpc":"2.0","method":"put","id":1.5}"#;
+        let req = JsonRpcRequest::parse(line).expect("non-standard id is accepted");
+        assert_eq!(req.id(), None);
+        assert_eq!(req.raw_id(), Some("1.5"));
+        assert!(req.has_id());
+
+        // The response echoes the id verbatim
+        let raw_id = req.raw_id().expect("raw id");
+        let response = JsonRpcResponse::format_result(raw_id, "ok");
+        let json = nojson::RawJson::parse(&response).expect("valid response");
+        let member = |name: &str| {
+            json.value()
+                .to_member(name)
+                .and_then(|v| v.required())
+                .map(|v| v.as_raw_str())
+                .expect("member")
+        };
+        assert_eq!(member("jsonrpc"), r#""2.0""#);
+        assert_eq!(member("id"), "1.5");
+        assert_eq!(member("result"), r#""ok""#);
+
+        // Standard ids can be parsed back by `JsonRpcResponse`
+        let response = JsonRpcResponse::format_result("7", 1);
+        let parsed = JsonRpcResponse::parse(&response).expect("valid response");
+        assert_eq!(parsed.id(), Some(&JsonRpcRequestId::Integer(7)));
+
+        let line = br#"{"jsonrpc":"2.0","method":"put","id":{"seq":1}}"#;
+        let req = JsonRpcRequest::parse(line).expect("non-standard id is accepted");
+        assert_eq!(req.raw_id(), Some(r#"{"seq":1}"#));
+        assert_eq!(req.into_owned().raw_id(), Some(r#"{"seq":1}"#));
+
+        let line = br#"{"jsonrpc":"2.0","method":"put","id":7}"#;
+        let req = JsonRpcRequest::parse(line).expect("valid request");
+        assert_eq!(req.id(), Some(&JsonRpcRequestId::Integer(7)));
+        assert_eq!(req.raw_id(), Some("7"));
+
+        let line = br#"{"jsonrpc":"2.0","method":"notify"}"#;
+        let req = JsonRpcRequest::parse(line).expect("valid notification");
+        assert_eq!(req.raw_id(), None);
+        assert!(!req.has_id());
+        assert!(!req.into_owned().has_id());
+    }
+
+    #[test]
+    fn parse_invalid_request_keeps_id() {
+        let line = br#"{"jsonrpc":"2.0","params":{"key":"a"},"id":"req-1"}"#;
+        let err = JsonRpcRequest::parse(line).expect_err("missing method");
+        assert_eq!(err.error, JsonRpcPredefinedError::InvalidRequest);
+        assert_eq!(err.id, Some(JsonRpcRequestId::String("req-1".to_owned())));
+
+        let line = br#"{"jsonrpc":"1.0","method":"put","id":3}"#;
+        let err = JsonRpcRequest::parse(line).expect_err("unsupported version");
+        assert_eq!(err.id, Some(JsonRpcRequestId::Integer(3)));
+
+        let line = br#"{"jsonrpc":"2.0","id":"#;
+        let err = JsonRpcRequest::parse(line).expect_err("malformed JSON");
+        assert_eq!(err.error, JsonRpcPredefinedError::ParseError);
+        assert_eq!(err.id, None);
+    }
+}
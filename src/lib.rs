@@ -1,20 +1,32 @@
+mod clock;
 mod error;
+mod machine;
 mod node_core;
 mod node_types;
+mod resolver;
+mod router;
 
 pub mod conv; // TODO: private
 pub mod jsonrpc;
 pub mod node;
 pub mod storage;
 
+pub use crate::clock::{Clock, ManualClock, SystemClock};
+pub use crate::conv::MessageLimits;
 pub use crate::jsonrpc::{
-    JsonRpcPredefinedError, JsonRpcRequest, JsonRpcRequestId, JsonRpcResponse,
+    JsonRpcPredefinedError, JsonRpcRequest, JsonRpcRequestError, JsonRpcRequestId, JsonRpcResponse,
+    OwnedJsonRpcRequest,
 };
+pub use crate::machine::StateMachine;
 pub use crate::node::{
-    Action, ApplyAction, Event, JsonValue, Node, NodeId, NodeRole, RecentCommands, StorageEntry,
+    Action, ApplyAction, CommitToken, DriveOutput, Event, InboundKind, JsonValue, Node, NodeConfig,
+    NodeId, NodeRole, ProposalDropReason, ProposalId, ProposalOutcome, ProposalStatus,
+    RecentCommands, SnapshotMeta, StorageEntry, Tick,
 };
-pub use crate::storage::FileStorage;
-pub use error::Error;
+pub use crate::resolver::{LocalhostResolver, Resolver, StaticResolver};
+pub use crate::router::{ResponseRouter, Route};
+pub use crate::storage::{FileStorage, StorageFormat, StorageReport};
+pub use error::{DecodeError, Error, InitError};
 
 pub type Result<T> = std::result::Result<T, Error>;
 
@@ -0,0 +1,18 @@
+use crate::node::JsonValue;
+
+/// User state machine driven by `Node::drive()`
+pub trait StateMachine {
+    /// Applies a committed command and returns its result
+    fn apply(&mut self, request: nojson::RawJsonValue<'_, '_>) -> JsonValue;
+
+    /// Answers a resolved query without modifying the state
+    ///
+    /// Queries are only resolved on the node that proposed them, so they must not change the state.
+    fn query(&self, request: nojson::RawJsonValue<'_, '_>) -> JsonValue;
+
+    /// Returns the current state, which is embedded in snapshots as the user machine
+    fn snapshot(&self) -> JsonValue;
+
+    /// Replaces the current state with the user machine of a loaded snapshot
+    fn restore(&mut self, snapshot: nojson::RawJsonValue<'_, '_>) -> crate::Result<()>;
+}
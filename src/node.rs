@@ -1,4 +1,6 @@
 pub use crate::node_core::Node;
 pub use crate::node_types::{
-    Action, ApplyAction, Event, JsonValue, NodeId, NodeRole, RecentCommands, StorageEntry,
+    Action, ApplyAction, CommitToken, DriveOutput, Event, InboundKind, JsonValue, NodeConfig,
+    NodeId, NodeRole, ProposalDropReason, ProposalId, ProposalOutcome, ProposalStatus,
+    RecentCommands, SnapshotMeta, StorageEntry, Tick,
 };
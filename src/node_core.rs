@@ -1,11 +1,25 @@
 #[path = "node_persist.rs"]
 mod node_persist;
 
+use crate::clock::Clock;
+use crate::error::{DecodeError, InitError};
+use crate::machine::StateMachine;
 use crate::node_types::{
-    Action, ApplyAction, Command, Event, JsonValue, NodeId, NodeRole, ProposalId, QueryMessage,
-    RecentCommands, StorageEntry,
+    Action, ApplyAction, ApplyHandler, Command, CommitToken, DriveOutput, Event, InboundKind,
+    JsonValue, NodeConfig, NodeId, NodeRole, PreVoteMessage, ProposalDropReason, ProposalId,
+    ProposalOutcome, ProposalStatus, QueryMessage, RecentCommands, StorageEntry, Tick,
 };
 
+const MAX_TRACKED_PROPOSALS: usize = 1024;
+const MAX_APPLIED_PROPOSAL_IDS: usize = 1024;
+
+const MAX_CANDIDATE_JITTER_MS: u64 = 50;
+
+// Identical replies received within this window are treated as redeliveries.
+// This is kept shorter than the heartbeat interval so that replies to consecutive heartbeats
+// are still processed.
+const DUPLICATE_REPLY_WINDOW: std::time::Duration = std::time::Duration::from_millis(25);
+
 #[derive(Debug, Clone)]
 pub struct Node {
     pub(crate) inner: noraft::Node,
@@ -14,13 +28,57 @@ pub struct Node {
     pub(crate) initialized: bool,
     pub(crate) local_command_seqno: u64,
     pub(crate) applied_index: noraft::LogIndex,
+    pub(crate) applied_user_index: noraft::LogIndex,
     pub(crate) pending_queries:
         std::collections::BTreeMap<(noraft::LogPosition, ProposalId), JsonValue>,
+    // Local queries waiting for `applied_index` to reach their commit token
+    pub(crate) token_queries: std::collections::BTreeMap<(noraft::LogIndex, ProposalId), JsonValue>,
+    // (proposed_at, deadline) of the queries proposed by this node
+    pub(crate) query_timings:
+        std::collections::BTreeMap<ProposalId, (std::time::Instant, Option<std::time::Instant>)>,
     pub(crate) last_role: noraft::Role,
+    pub(crate) proposals: std::collections::BTreeMap<ProposalId, Option<noraft::LogPosition>>,
+    // Commands proposed while the leader is unknown
+    pub(crate) pending_proposals: std::collections::VecDeque<JsonValue>,
+    pub(crate) max_pending_proposals: usize,
+    pub(crate) apply_handler: ApplyHandler,
+    pub(crate) snapshot_threshold: Option<usize>,
+    pub(crate) pending_snapshot_index: Option<noraft::LogIndex>,
+    pub(crate) clock: std::sync::Arc<dyn Clock>,
+    pub(crate) heartbeat_interval: std::time::Duration,
+    pub(crate) election_timeout: std::time::Duration,
+    pub(crate) message_limits: crate::conv::MessageLimits,
+    pub(crate) timeout_deadline: Option<std::time::Instant>,
+    pub(crate) leader_lease: Option<std::time::Duration>,
+    pub(crate) follower_acks: std::collections::BTreeMap<NodeId, std::time::Instant>,
+    // Time at which the last AppendEntries call from the current leader was received
+    pub(crate) last_leader_contact: Option<std::time::Instant>,
+    pub(crate) pre_vote: bool,
+    // Proposed term and the voters that granted it in the ongoing pre-vote round
+    pub(crate) pre_votes: Option<(noraft::Term, std::collections::BTreeSet<NodeId>)>,
+    pub(crate) follower_match_indices: std::collections::BTreeMap<NodeId, noraft::LogIndex>,
+    pub(crate) recent_commands_bytes: usize,
+    pub(crate) recent_commands_bytes_limit: Option<usize>,
+    pub(crate) snapshot_needed_notified: bool,
+    pub(crate) unsynced_storage_entries: bool,
+    pub(crate) applied_proposal_ids: std::collections::BTreeSet<ProposalId>,
+    pub(crate) applied_proposal_order: std::collections::VecDeque<ProposalId>,
+    pub(crate) recent_replies:
+        std::collections::BTreeMap<NodeId, (noraft::Message, std::time::Instant)>,
 }
 
 impl Node {
     pub fn start(id: NodeId) -> Self {
+        Self::start_with_config(id, NodeConfig::default())
+    }
+
+    /// Same as `start()`, but tunes the node with `config`
+    ///
+    /// Panics if `config.message_limits` is invalid (see `MessageLimits::validate()`).
+    pub fn start_with_config(id: NodeId, config: NodeConfig) -> Self {
+        if let Err(e) = config.message_limits.validate() {
+            panic!("invalid message limits: {e}");
+        }
         let mut action_queue = std::collections::VecDeque::new();
         let inner = noraft::Node::start(id.into_inner());
         let last_role = inner.role();
@@ -34,8 +92,35 @@ impl Node {
             initialized: false,
             local_command_seqno: 0,
             applied_index: noraft::LogIndex::ZERO,
+            applied_user_index: noraft::LogIndex::ZERO,
             pending_queries: std::collections::BTreeMap::new(),
+            token_queries: std::collections::BTreeMap::new(),
+            query_timings: std::collections::BTreeMap::new(),
             last_role,
+            proposals: std::collections::BTreeMap::new(),
+            pending_proposals: std::collections::VecDeque::new(),
+            max_pending_proposals: config.max_pending_proposals,
+            apply_handler: ApplyHandler::default(),
+            snapshot_threshold: config.snapshot_threshold,
+            pending_snapshot_index: None,
+            clock: config.clock,
+            heartbeat_interval: config.heartbeat_interval,
+            election_timeout: config.election_timeout,
+            message_limits: config.message_limits,
+            timeout_deadline: None,
+            leader_lease: config.leader_lease,
+            follower_acks: std::collections::BTreeMap::new(),
+            last_leader_contact: None,
+            pre_vote: config.pre_vote,
+            pre_votes: None,
+            follower_match_indices: std::collections::BTreeMap::new(),
+            recent_commands_bytes: 0,
+            recent_commands_bytes_limit: config.recent_commands_bytes_limit,
+            snapshot_needed_notified: false,
+            unsynced_storage_entries: false,
+            applied_proposal_ids: std::collections::BTreeSet::new(),
+            recent_replies: std::collections::BTreeMap::new(),
+            applied_proposal_order: std::collections::VecDeque::new(),
         }
     }
 
@@ -43,6 +128,11 @@ impl Node {
         NodeId::from_inner(self.inner.id())
     }
 
+    /// Returns the generation of this node, which is incremented each time the node is restarted via `load()`
+    pub fn generation(&self) -> u64 {
+        self.inner.generation().get()
+    }
+
     pub fn members(&self) -> impl Iterator<Item = NodeId> + '_ {
         self.inner.config().unique_nodes().map(NodeId::from_inner)
     }
@@ -51,6 +141,70 @@ impl Node {
         self.members().filter(|id| *id != self.id())
     }
 
+    /// Returns the last known match index of each follower (empty unless this node is the leader)
+    ///
+    /// Followers that have not replied to the current leader yet are reported with index 0.
+    pub fn follower_progress(&self) -> Vec<(NodeId, noraft::LogIndex)> {
+        if !self.is_leader() {
+            return Vec::new();
+        }
+        self.peers()
+            .map(|id| {
+                let index = self
+                    .follower_match_indices
+                    .get(&id)
+                    .copied()
+                    .unwrap_or(noraft::LogIndex::ZERO);
+                (id, index)
+            })
+            .collect()
+    }
+
+    pub fn role(&self) -> NodeRole {
+        NodeRole::from_inner(self.inner.role())
+    }
+
+    /// Returns the ID of the leader known to this node
+    ///
+    /// On a follower, this is the node it voted for in the current term, which may be stale
+    /// or may not have won the election yet.
+    pub fn leader_id(&self) -> Option<NodeId> {
+        if self.is_leader() {
+            return Some(self.id());
+        }
+        let leader = self.inner.voted_for()?;
+        let leader = NodeId::from_inner(leader);
+        (leader != self.id()).then_some(leader)
+    }
+
+    pub fn current_term(&self) -> noraft::Term {
+        self.inner.current_term()
+    }
+
+    pub fn commit_index(&self) -> noraft::LogIndex {
+        self.inner.commit_index()
+    }
+
+    /// Returns `true` if the latest (possibly uncommitted) cluster configuration is in joint consensus
+    ///
+    /// Membership changes cannot be stacked, so applications should wait until this returns `false`
+    /// before issuing another one.
+    pub fn is_in_joint_consensus(&self) -> bool {
+        !self.inner.config().new_voters.is_empty()
+    }
+
+    /// Returns the old and new voters while a membership change is in progress
+    pub fn pending_config(&self) -> Option<(Vec<NodeId>, Vec<NodeId>)> {
+        if !self.is_in_joint_consensus() {
+            return None;
+        }
+        let config = self.inner.config();
+        let to_node_ids = |voters: &std::collections::BTreeSet<noraft::NodeId>| {
+            voters.iter().copied().map(NodeId::from_inner).collect()
+        };
+        Some((to_node_ids(&config.voters), to_node_ids(&config.new_voters)))
+    }
+
     pub fn is_leader(&self) -> bool {
         self.inner.role().is_leader()
     }
@@ -63,12 +217,13 @@ impl Node {
         self.inner.role().is_candidate()
     }
 
-    pub fn init_cluster(&mut self, members: &[NodeId]) -> bool {
+    /// Creates a new cluster consisting of `members`, which must include this node
+    pub fn init_cluster(&mut self, members: &[NodeId]) -> Result<(), InitError> {
         if self.initialized {
-            return false;
+            return Err(InitError::AlreadyInitialized);
         }
         if !members.contains(&self.id()) {
-            return false;
+            return Err(InitError::NotAMember);
         }
 
         let members: Vec<_> = members.iter().copied().map(NodeId::into_inner).collect();
@@ -76,13 +231,89 @@ impl Node {
         self.maybe_emit_role_events();
         self.initialized = true;
 
-        true
+        Ok(())
+    }
+
+    /// Registers a handler that is invoked synchronously for each committed command and resolved query
+    ///
+    /// If the handler returns `true`, the entry is regarded as handled and no `Action::Apply` is queued for it.
+    /// Otherwise, the corresponding `Action::Apply` is queued as usual (e.g., to send a reply from the action loop).
+    ///
+    /// Note that clones of this node share the same handler.
+    pub fn set_apply_handler<F>(&mut self, handler: F)
+    where
+        F: 'static + Send + FnMut(&ApplyAction) -> bool,
+    {
+        self.apply_handler = ApplyHandler::new(handler);
+    }
+
+    pub fn clear_apply_handler(&mut self) {
+        self.apply_handler = ApplyHandler::default();
+    }
+
+    /// Returns the index up to which the committed log entries have been processed by this node
+    ///
+    /// This includes entries that do not produce `Action::Apply` (e.g., cluster configuration changes and queries).
+    pub fn applied_index(&self) -> noraft::LogIndex {
+        self.applied_index
+    }
+
+    /// Returns the index of the last user command emitted as `Action::Apply`
+    ///
+    /// After `load()` with a snapshot, this is the snapshot position (the user machine reflects it).
+    pub fn applied_user_index(&self) -> noraft::LogIndex {
+        self.applied_user_index
+    }
+
+    /// Sets the number of in-memory commands that makes `take_snapshot_if_needed()` create a snapshot
+    ///
+    /// `None` (the default) disables the automatic snapshot.
+    pub fn set_snapshot_threshold(&mut self, threshold: Option<usize>) {
+        self.snapshot_threshold = threshold;
     }
 
     pub fn recent_commands(&self) -> &RecentCommands {
         &self.recent_commands
     }
 
+    /// Returns the total serialized size of the commands kept in memory
+    pub fn recent_commands_bytes(&self) -> usize {
+        self.recent_commands_bytes
+    }
+
+    /// Sets a soft limit on `recent_commands_bytes()` (`None` by default)
+    ///
+    /// When the limit is exceeded, `Event::SnapshotNeeded` is emitted once instead of
+    /// the memory usage silently growing. The notification is re-armed after the memory
+    /// log has been stripped below the limit.
+    pub fn set_recent_commands_bytes_limit(&mut self, limit: Option<usize>) {
+        self.recent_commands_bytes_limit = limit;
+        self.maybe_emit_snapshot_needed_event();
+    }
+
+    fn maybe_emit_snapshot_needed_event(&mut self) {
+        let Some(limit) = self.recent_commands_bytes_limit else {
+            return;
+        };
+        if self.recent_commands_bytes <= limit {
+            self.snapshot_needed_notified = false;
+            return;
+        }
+        if self.snapshot_needed_notified {
+            return;
+        }
+        self.snapshot_needed_notified = true;
+        self.push_action(Action::NotifyEvent(Event::SnapshotNeeded {
+            recent_commands_bytes: self.recent_commands_bytes,
+        }));
+    }
+
+    pub(crate) fn reset_recent_commands(&mut self, recent_commands: RecentCommands) {
+        self.recent_commands_bytes = recent_commands.values().map(JsonValue::byte_len).sum();
+        self.recent_commands = recent_commands;
+        self.maybe_emit_snapshot_needed_event();
+    }
+
     pub fn strip_memory_log(&mut self, index: noraft::LogIndex) -> bool {
         if index > self.applied_index {
             return false;
@@ -101,7 +332,8 @@ impl Node {
         }
 
         let i = noraft::LogIndex::new(index.get() + 1);
-        self.recent_commands = self.recent_commands.split_off(&i);
+        let recent_commands = self.recent_commands.split_off(&i);
+        self.reset_recent_commands(recent_commands);
         true
     }
 
@@ -114,44 +346,342 @@ impl Node {
     // - Uninitialized cluster
     // - re-election
 
-    fn propose(&mut self, command: Command) {
-        let value = JsonValue::new(command);
-        self.propose_command_value(value);
+    fn propose(&mut self, command: Command) -> bool {
+        self.propose_encoded(command, None)
+    }
+
+    /// Same as `propose()`, but proposes `encoded` (the serialized form of `command`, if available)
+    /// instead of the re-serialized `command`
+    fn propose_encoded(&mut self, command: Command, encoded: Option<JsonValue>) -> bool {
+        if let Command::RemoveNode {
+            proposal_id,
+            node_id,
+        } = command
+            && self.is_leader()
+        {
+            // Membership changes are not log commands, so the leader proposes them as a new config
+            return self.propose_remove_node(proposal_id, node_id);
+        }
+
+        let value = encoded.unwrap_or_else(|| JsonValue::new(command));
+        self.propose_command_value(value)
+    }
+
+    fn propose_remove_node(&mut self, proposal_id: ProposalId, node_id: NodeId) -> bool {
+        // Membership changes cannot be stacked during joint consensus
+        if self.is_in_joint_consensus() {
+            return false;
+        }
+        let config = self.inner.config();
+        if !config.voters.contains(&node_id.into_inner()) {
+            return false;
+        }
+
+        let new_config = config.to_joint_consensus(&[], &[node_id.into_inner()]);
+        let position = self.inner.propose_config(new_config);
+        if proposal_id.is_proposer(self.id(), self.generation()) {
+            self.proposals.insert(proposal_id, Some(position));
+            self.trim_proposals();
+        }
+        true
+    }
+
+    /// Proposes the removal of this node from the cluster
+    ///
+    /// The proposal is redirected to the leader if this node is a follower.
+    /// Once the removal is committed, `is_decommissioned()` returns `true` and
+    /// the application can safely shut down the process.
+    pub fn remove_self(&mut self) -> ProposalId {
+        let proposal_id = self.next_proposal_id();
+        let command = Command::RemoveNode {
+            proposal_id,
+            node_id: self.id(),
+        };
+        if self.propose(command) {
+            self.proposals.entry(proposal_id).or_insert(None);
+            self.trim_proposals();
+        }
+        proposal_id
     }
 
-    // TODO: in redirected case, this serialization can be eliminated
-    fn propose_command_value(&mut self, command: JsonValue) {
+    /// Returns `true` if the committed (final, non-joint) cluster configuration no longer includes this node
+    ///
+    /// While the removal is still in joint consensus, this node is needed for the old majority,
+    /// so this returns `false` until the final configuration is committed.
+    pub fn is_decommissioned(&self) -> bool {
         if !self.initialized {
-            return;
+            return false;
+        }
+        let Some((_, config)) = self
+            .inner
+            .log()
+            .get_position_and_config(self.inner.commit_index())
+        else {
+            return false;
+        };
+        if !config.new_voters.is_empty() {
+            return false;
+        }
+        !config.voters.is_empty() && !config.voters.contains(&self.id().into_inner())
+    }
+
+    fn propose_command_value(&mut self, command: JsonValue) -> bool {
+        if !self.initialized {
+            return false;
+        }
+
+        // Otherwise the entry would be rejected by every follower on each retry
+        if !crate::conv::command_fits_limits(&command, self.message_limits) {
+            if let Ok(Some(proposal_id)) = command.get_optional_member("proposal_id") {
+                self.push_action(Action::NotifyEvent(Event::ProposalDropped {
+                    proposal_id,
+                    reason: ProposalDropReason::CommandTooLarge,
+                }));
+            }
+            return false;
         }
 
         if !self.is_leader() {
             if let Some(maybe_leader) = self.leader_id() {
                 self.push_action(Action::Send(maybe_leader, command));
-            } else {
-                // TODO: add missing proposal event
+                return true;
             }
-            return;
+            return self.buffer_pending_proposal(command);
+        }
+
+        // Commands redirected from followers are subject to the lease as well
+        if !self.has_valid_lease(self.clock.now())
+            && let Ok(Some(proposal_id)) = command.get_optional_member("proposal_id")
+        {
+            self.push_action(Action::NotifyEvent(Event::ProposalDropped {
+                proposal_id,
+                reason: ProposalDropReason::LeaseExpired,
+            }));
+            return false;
         }
 
         let position = self.inner.propose_command();
-        self.recent_commands.insert(position.index, command);
+        self.insert_recent_command(position, command);
+        true
+    }
+
+    /// Sets the maximum number of commands buffered while the leader is unknown (1024 by default)
+    ///
+    /// The buffered commands are forwarded once a leader becomes known.
+    /// When the buffer is full, the oldest command is dropped with `Event::ProposalDropped`.
+    pub fn set_max_pending_proposals(&mut self, max: usize) {
+        self.max_pending_proposals = max;
+        self.trim_pending_proposals();
+    }
+
+    fn buffer_pending_proposal(&mut self, command: JsonValue) -> bool {
+        if self.max_pending_proposals == 0 {
+            self.drop_pending_proposal(&command);
+            return false;
+        }
+        self.pending_proposals.push_back(command);
+        self.trim_pending_proposals();
+        true
+    }
+
+    fn trim_pending_proposals(&mut self) {
+        while self.pending_proposals.len() > self.max_pending_proposals {
+            if let Some(command) = self.pending_proposals.pop_front() {
+                self.drop_pending_proposal(&command);
+            }
+        }
+    }
+
+    fn drop_pending_proposal(&mut self, command: &JsonValue) {
+        let proposal_id: Option<ProposalId> =
+            command.get_optional_member("proposal_id").ok().flatten();
+        if let Some(proposal_id) = proposal_id {
+            self.proposals.remove(&proposal_id);
+            self.push_action(Action::NotifyEvent(Event::ProposalDropped {
+                proposal_id,
+                reason: ProposalDropReason::PendingProposalsFull,
+            }));
+        }
+    }
+
+    fn flush_pending_proposals(&mut self) {
+        if self.pending_proposals.is_empty() || self.leader_id().is_none() {
+            return;
+        }
+        for value in std::mem::take(&mut self.pending_proposals) {
+            // Decoded again so that a buffered `RemoveNode` is proposed as a config change
+            let command = Command::try_from(value.get()).expect("bug");
+            self.propose_encoded(command, Some(value));
+        }
+    }
+
+    /// Proposes a no-op command that advances the commit index without emitting `Action::Apply`
+    ///
+    /// This can be used as a barrier, e.g., to confirm that entries from previous terms are committed.
+    /// Returns `false` if the command could not be proposed (e.g., the leader is unknown).
+    pub fn propose_noop(&mut self) -> bool {
+        self.propose(Command::Noop)
     }
 
     pub fn propose_command<S: nojson::DisplayJson, T: nojson::DisplayJson>(
         &mut self,
         source: S,
         request: T,
-    ) {
-        let source = JsonValue::new(source);
-        let request = JsonValue::new(request);
+    ) -> ProposalId {
+        self.propose_apply(JsonValue::new(source), JsonValue::new(request), None)
+    }
+
+    /// Same as `propose_command()`, but also returns the log index assigned to the command
+    ///
+    /// The index is only available when this node is the leader; redirected or dropped
+    /// proposals have no assigned index.
+    pub fn propose_command_tracked<S: nojson::DisplayJson, T: nojson::DisplayJson>(
+        &mut self,
+        source: S,
+        request: T,
+    ) -> ProposalOutcome {
+        let id = self.propose_command(source, request);
+        let assigned_index = self
+            .proposals
+            .get(&id)
+            .copied()
+            .flatten()
+            .map(|position| position.index);
+        ProposalOutcome { id, assigned_index }
+    }
+
+    /// Same as `propose_command()`, but attaches `meta` (e.g., a trace id) to the command
+    ///
+    /// The metadata is stored in the log along with the command and is available via
+    /// `ApplyAction::meta()` on every node that applies the command.
+    pub fn propose_command_with_meta<S, T, M>(
+        &mut self,
+        source: S,
+        request: T,
+        meta: M,
+    ) -> ProposalId
+    where
+        S: nojson::DisplayJson,
+        T: nojson::DisplayJson,
+        M: nojson::DisplayJson,
+    {
+        let meta = JsonValue::new(meta);
+        self.propose_apply(JsonValue::new(source), JsonValue::new(request), Some(meta))
+    }
+
+    fn propose_apply(
+        &mut self,
+        source: JsonValue,
+        request: JsonValue,
+        meta: Option<JsonValue>,
+    ) -> ProposalId {
         let proposal_id = self.next_proposal_id();
         let command = Command::Apply {
             proposal_id,
             source,
             command: request,
+            meta,
         };
-        self.propose(command);
+        if self.propose(command) {
+            self.proposals.entry(proposal_id).or_insert(None);
+            self.trim_proposals();
+        }
+        proposal_id
+    }
+
+    /// Enables (or disables with `None`) the leader lease
+    ///
+    /// When enabled, a leader that has not received acknowledgements (AppendEntries replies)
+    /// from a majority within `lease` refuses new commands (including those redirected from
+    /// followers) with `Event::ProposalDropped`,
+    /// so that a stale leader (e.g., isolated by a network partition) does not accept doomed writes.
+    /// Heartbeats are sent on leader timeouts, so `lease` should be longer than the heartbeat interval.
+    pub fn set_leader_lease(&mut self, lease: Option<std::time::Duration>) {
+        self.leader_lease = lease;
+    }
+
+    /// Returns whether this node is the leader and holds a valid lease at `now`
+    ///
+    /// If the leader lease is disabled, this is equivalent to `is_leader()`.
+    pub fn has_valid_lease(&self, now: std::time::Instant) -> bool {
+        if !self.is_leader() {
+            return false;
+        }
+        let Some(lease) = self.leader_lease else {
+            return true;
+        };
+
+        let is_acked = |id: &noraft::NodeId| {
+            let id = NodeId::from_inner(*id);
+            id == self.id()
+                || self
+                    .follower_acks
+                    .get(&id)
+                    .is_some_and(|t| now.saturating_duration_since(*t) < lease)
+        };
+        let config = self.inner.config();
+        let has_majority = |voters: &std::collections::BTreeSet<noraft::NodeId>| {
+            voters.is_empty() || voters.iter().filter(|id| is_acked(id)).count() * 2 > voters.len()
+        };
+        has_majority(&config.voters) && has_majority(&config.new_voters)
+    }
+
+    /// Returns the time elapsed since this node last received an AppendEntries call from the leader
+    ///
+    /// A follower cut off from the leader (e.g., by a network partition) stops advancing its
+    /// commit index without any error, so the application can use this to detect the situation
+    /// (e.g., to refuse stale reads or to raise an alert).
+    /// Returns `Some(Duration::ZERO)` on the leader, and `None` if no leader has contacted this node
+    /// since it was started or loaded.
+    pub fn last_heard_from_leader(&self, now: std::time::Instant) -> Option<std::time::Duration> {
+        if self.is_leader() {
+            return Some(std::time::Duration::ZERO);
+        }
+        self.last_leader_contact
+            .map(|t| now.saturating_duration_since(t))
+    }
+
+    /// Returns the status of a command proposed by this node
+    ///
+    /// Only the most recent proposals are tracked, and the tracking is reset by `load()`.
+    /// A proposal that was dropped before reaching the leader, or whose log entry was
+    /// overwritten or already stripped from the memory log, is reported as `NotFound`.
+    pub fn proposal_status(&self, proposal_id: ProposalId) -> ProposalStatus {
+        let Some(position) = self.proposals.get(&proposal_id) else {
+            return ProposalStatus::NotFound;
+        };
+        let Some(position) = *position else {
+            return ProposalStatus::Pending;
+        };
+        match self.inner.get_commit_status(position) {
+            noraft::CommitStatus::InProgress => ProposalStatus::Pending,
+            noraft::CommitStatus::Committed => ProposalStatus::Committed(position.index),
+            noraft::CommitStatus::Rejected | noraft::CommitStatus::Unknown => {
+                ProposalStatus::NotFound
+            }
+        }
+    }
+
+    fn insert_recent_command(&mut self, position: noraft::LogPosition, command: JsonValue) {
+        let proposal_id: Option<ProposalId> =
+            command.get_optional_member("proposal_id").ok().flatten();
+        if let Some(proposal_id) = proposal_id
+            && proposal_id.is_proposer(self.id(), self.generation())
+        {
+            self.proposals.insert(proposal_id, Some(position));
+            self.trim_proposals();
+        }
+        self.recent_commands_bytes += command.byte_len();
+        if let Some(old) = self.recent_commands.insert(position.index, command) {
+            self.recent_commands_bytes -= old.byte_len();
+        }
+        self.maybe_emit_snapshot_needed_event();
+    }
+
+    fn trim_proposals(&mut self) {
+        while self.proposals.len() > MAX_TRACKED_PROPOSALS {
+            self.proposals.pop_first();
+        }
     }
 
     fn get_next_broadcast_position(&self) -> Option<noraft::LogPosition> {
@@ -172,14 +702,106 @@ impl Node {
 
         let value = JsonValue::new(Command::Query);
         let position = self.inner.propose_command();
-        self.recent_commands.insert(position.index, value);
+        self.insert_recent_command(position, value);
         position
     }
 
-    pub fn propose_query<T: nojson::DisplayJson>(&mut self, request: T) {
+    /// Proposes a linearizable read
+    ///
+    /// Queries are coalesced: all queries proposed on the leader before the next `next_action()`
+    /// call share a single log position (either the first entry of the pending broadcast or a
+    /// single `Query` command), and are resolved together as one `Action::Apply` per query
+    /// once that position is committed.
+    /// Queries redirected from followers are coalesced in the same way.
+    ///
+    /// A query is resolved as a single `Action::Apply`, so a request carrying several read
+    /// operations (e.g., a batch of keys) observes one consistent machine state when it is
+    /// fulfilled upon that `Apply`: no command is applied between the individual reads.
+    ///
+    /// The resulting `Action::Apply` reports the waiting time via `ApplyAction::query_elapsed()`.
+    /// If no leader is known, the query is dropped with `ProposalDropReason::LeaderUnknown`.
+    pub fn propose_query<T: nojson::DisplayJson>(&mut self, request: T) -> ProposalId {
+        self.propose_query_with_optional_deadline(request, None)
+    }
+
+    /// Same as `propose_query()`, but drops the query if it is not resolved by `deadline`
+    ///
+    /// A dropped query is reported by `Event::ProposalDropped` with
+    /// `ProposalDropReason::QueryDeadlineExceeded` instead of `Action::Apply`.
+    pub fn propose_query_with_deadline<T: nojson::DisplayJson>(
+        &mut self,
+        request: T,
+        deadline: std::time::Instant,
+    ) -> ProposalId {
+        self.propose_query_with_optional_deadline(request, Some(deadline))
+    }
+
+    fn propose_query_with_optional_deadline<T: nojson::DisplayJson>(
+        &mut self,
+        request: T,
+        deadline: Option<std::time::Instant>,
+    ) -> ProposalId {
         let request = JsonValue::new(request);
         let proposal_id = self.next_proposal_id();
+        self.track_query(proposal_id, deadline);
         self.propose_query_inner(proposal_id, request);
+        proposal_id
+    }
+
+    /// Starts tracking a query proposed by this node, dropping the oldest ones beyond the limit
+    fn track_query(&mut self, proposal_id: ProposalId, deadline: Option<std::time::Instant>) {
+        self.query_timings
+            .insert(proposal_id, (self.clock.now(), deadline));
+        while self.query_timings.len() > MAX_TRACKED_PROPOSALS {
+            if let Some((evicted, _)) = self.query_timings.pop_first() {
+                self.drop_query(evicted, ProposalDropReason::TooManyPendingQueries);
+            }
+        }
+    }
+
+    /// Proposes a local read that is answered once this node has applied the write identified by `token`
+    ///
+    /// Unlike `propose_query()`, no round trip to the leader is made, so the read is not
+    /// linearizable, but it is guaranteed to observe the write that produced `token`
+    /// (see `ApplyAction::commit_token()`).
+    /// If `token` has already been applied, `Action::Apply` is queued immediately;
+    /// otherwise it is emitted from `next_action()` once `applied_index()` reaches `token`.
+    ///
+    /// Pending reads count toward the same limit as `propose_query()`: the oldest ones are
+    /// dropped with `ProposalDropReason::TooManyPendingQueries`. They are discarded by `load()`.
+    pub fn propose_local_query_after<T: nojson::DisplayJson>(
+        &mut self,
+        token: CommitToken,
+        request: T,
+    ) -> ProposalId {
+        self.propose_local_query_after_with_optional_deadline(token, request, None)
+    }
+
+    /// Same as `propose_local_query_after()`, but drops the read if `token` is not applied by `deadline`
+    ///
+    /// A dropped read is reported by `Event::ProposalDropped` with
+    /// `ProposalDropReason::QueryDeadlineExceeded` instead of `Action::Apply`.
+    pub fn propose_local_query_after_with_deadline<T: nojson::DisplayJson>(
+        &mut self,
+        token: CommitToken,
+        request: T,
+        deadline: std::time::Instant,
+    ) -> ProposalId {
+        self.propose_local_query_after_with_optional_deadline(token, request, Some(deadline))
+    }
+
+    fn propose_local_query_after_with_optional_deadline<T: nojson::DisplayJson>(
+        &mut self,
+        token: CommitToken,
+        request: T,
+        deadline: Option<std::time::Instant>,
+    ) -> ProposalId {
+        let request = JsonValue::new(request);
+        let proposal_id = self.next_proposal_id();
+        self.track_query(proposal_id, deadline);
+        self.token_queries.insert((token.0, proposal_id), request);
+        self.emit_token_query_actions();
+        proposal_id
     }
 
     fn propose_query_inner(&mut self, proposal_id: ProposalId, request: JsonValue) {
@@ -197,7 +819,9 @@ impl Node {
             let message = JsonValue::new(query_message);
             self.push_action(Action::Send(maybe_leader_id, message));
         } else {
-            // TODO: add missing proposal event
+            // Unlike commands, queries are not buffered until a leader becomes known
+            self.query_timings.remove(&proposal_id);
+            self.drop_query(proposal_id, ProposalDropReason::LeaderUnknown);
         }
     }
 
@@ -229,43 +853,258 @@ impl Node {
     }
 
     fn next_proposal_id(&mut self) -> ProposalId {
-        let proposal_id = ProposalId::new(
-            self.id(),
-            self.inner.generation().get(),
-            self.local_command_seqno,
-        );
+        let proposal_id = ProposalId::new(self.id(), self.generation(), self.local_command_seqno);
         self.local_command_seqno += 1;
         proposal_id
     }
 
-    fn leader_id(&self) -> Option<NodeId> {
-        let leader = self.inner.voted_for()?;
-        let leader = NodeId::from_inner(leader);
-        (leader != self.id()).then_some(leader)
-    }
-
     pub(crate) fn push_action(&mut self, action: Action) {
+        match action {
+            Action::AppendStorageEntry(_) => {
+                self.unsynced_storage_entries = true;
+            }
+            Action::Broadcast(_) | Action::Send(..) | Action::SendSnapshot(_)
+                if self.unsynced_storage_entries =>
+            {
+                self.unsynced_storage_entries = false;
+                self.action_queue.push_back(Action::SyncStorage);
+            }
+            _ => {}
+        }
         self.action_queue.push_back(action);
     }
 
+    /// Replaces the clock used to compute `timeout_deadline()` (`SystemClock` by default)
+    pub fn set_clock<C: 'static + Clock>(&mut self, clock: C) {
+        self.clock = std::sync::Arc::new(clock);
+    }
+
+    /// Returns the time at which `handle_timeout()` should be called
+    ///
+    /// The deadline is updated each time `Action::SetTimeout` is emitted.
+    pub fn timeout_deadline(&self) -> Option<std::time::Instant> {
+        self.timeout_deadline
+    }
+
+    /// Calls `handle_timeout()` if the current time of the clock has reached `timeout_deadline()`
+    pub fn handle_timeout_if_expired(&mut self) -> bool {
+        let Some(deadline) = self.timeout_deadline else {
+            return false;
+        };
+        if self.clock.now() < deadline {
+            return false;
+        }
+        self.timeout_deadline = None;
+        self.handle_timeout();
+        true
+    }
+
+    /// Handles the timeout if `now` has reached `timeout_deadline()`, then drains all pending actions
+    ///
+    /// This is a shortcut for the typical event loop step:
+    /// `handle_timeout()` when due, `next_action()` until `None`, and computing the next wake-up time.
+    /// If no timeout is scheduled (e.g., before `init_cluster()`), `next_deadline` is
+    /// `now` plus the election timeout so that the caller keeps polling.
+    pub fn tick(&mut self, now: std::time::Instant) -> Tick {
+        if let Some(deadline) = self.timeout_deadline
+            && deadline <= now
+        {
+            self.timeout_deadline = None;
+            self.handle_timeout();
+        }
+
+        let mut actions = Vec::new();
+        let mut events = Vec::new();
+        while let Some(action) = self.next_action() {
+            match action {
+                Action::NotifyEvent(event) => events.push(event),
+                action => actions.push(action),
+            }
+        }
+
+        let next_deadline = self.timeout_deadline.unwrap_or(now + self.election_timeout);
+        Tick {
+            actions,
+            events,
+            next_deadline,
+        }
+    }
+
+    fn timeout_duration(&self) -> std::time::Duration {
+        match self.inner.role() {
+            noraft::Role::Leader => self.heartbeat_interval,
+            noraft::Role::Follower => self.election_timeout,
+            noraft::Role::Candidate => {
+                // Random jitter to reduce the chance of split votes
+                // (`RandomState` is randomly seeded, so no RNG dependency is needed)
+                use std::hash::BuildHasher;
+                let seed = std::collections::hash_map::RandomState::new()
+                    .hash_one((self.id().get(), self.inner.current_term().get()));
+                self.election_timeout
+                    + std::time::Duration::from_millis(seed % MAX_CANDIDATE_JITTER_MS)
+            }
+        }
+    }
+
     pub fn handle_timeout(&mut self) {
+        if self.pre_vote && !self.is_leader() && self.start_pre_vote() {
+            return;
+        }
+        self.start_election();
+    }
+
+    fn start_election(&mut self) {
+        self.pre_votes = None;
         self.inner.handle_election_timeout();
+        if self.inner.role().is_candidate() {
+            self.push_action(Action::NotifyEvent(Event::ElectionTimeout {
+                term: self.inner.current_term(),
+            }));
+        }
         self.maybe_emit_role_events();
     }
 
+    /// Starts a pre-vote round, returning `false` if the election can start right away
+    fn start_pre_vote(&mut self) -> bool {
+        let config = self.inner.config();
+        let id = self.id().into_inner();
+        if !config.voters.contains(&id) && !config.new_voters.contains(&id) {
+            return false;
+        }
+
+        let term = noraft::Term::new(self.inner.current_term().get() + 1);
+        self.pre_votes = Some((term, std::collections::BTreeSet::from([self.id()])));
+        if self.has_pre_vote_majority() {
+            return false;
+        }
+
+        let call = PreVoteMessage::Call {
+            from: self.id(),
+            term,
+            last_position: self.inner.log().entries().last_position(),
+        };
+        self.push_action(Action::Broadcast(JsonValue::new(call)));
+
+        // Retry the round if it does not complete within the election timeout
+        self.handle_set_election_timeout();
+        true
+    }
+
+    fn has_pre_vote_majority(&self) -> bool {
+        let Some((_, granted)) = &self.pre_votes else {
+            return false;
+        };
+        let config = self.inner.config();
+        let has_majority = |voters: &std::collections::BTreeSet<noraft::NodeId>| {
+            voters.is_empty()
+                || voters
+                    .iter()
+                    .filter(|id| granted.contains(&NodeId::from_inner(**id)))
+                    .count()
+                    * 2
+                    > voters.len()
+        };
+        has_majority(&config.voters) && has_majority(&config.new_voters)
+    }
+
+    fn handle_pre_vote_message(&mut self, message_value: nojson::RawJsonValue<'_, '_>) -> bool {
+        let Ok(message) = PreVoteMessage::try_from(message_value) else {
+            return false;
+        };
+        self.initialize_if_needed();
+        match message {
+            PreVoteMessage::Call {
+                from,
+                term,
+                last_position,
+            } => {
+                // Deny while a leader is known to be alive, as in a real election this node
+                // would not have timed out yet
+                let leader_alive = self
+                    .last_heard_from_leader(self.clock.now())
+                    .is_some_and(|elapsed| elapsed < self.election_timeout);
+                let own_position = self.inner.log().entries().last_position();
+                let up_to_date = (last_position.term.get(), last_position.index.get())
+                    >= (own_position.term.get(), own_position.index.get());
+                let granted = term > self.inner.current_term() && up_to_date && !leader_alive;
+                let reply = PreVoteMessage::Reply {
+                    from: self.id(),
+                    term,
+                    granted,
+                };
+                self.push_action(Action::Send(from, JsonValue::new(reply)));
+            }
+            PreVoteMessage::Reply {
+                from,
+                term,
+                granted,
+            } => {
+                let Some((round_term, voters)) = &mut self.pre_votes else {
+                    return true;
+                };
+                if !granted || term != *round_term {
+                    return true;
+                }
+                voters.insert(from);
+                if !self.is_leader() && self.has_pre_vote_majority() {
+                    self.start_election();
+                }
+            }
+        }
+        true
+    }
+
     pub fn handle_message(&mut self, message_value: nojson::RawJsonValue<'_, '_>) -> bool {
-        let Ok(message) = crate::conv::json_to_message(message_value) else {
-            if self.handle_redirected_command(message_value) {
-                return true;
+        self.handle_message_classified(message_value).is_some()
+    }
+
+    /// Same as `handle_message()`, but also reports what kind of message was handled
+    ///
+    /// Returns `None` if the message was not understood and has been ignored.
+    pub fn handle_message_classified(
+        &mut self,
+        message_value: nojson::RawJsonValue<'_, '_>,
+    ) -> Option<InboundKind> {
+        let message = crate::conv::json_to_message_with_limits(message_value, self.message_limits);
+        let Ok(message) = message else {
+            if let Some(kind) = self.handle_redirected_command(message_value) {
+                return Some(kind);
             }
-            if self.handle_query_message(message_value) {
-                return true;
+            if let Some(kind) = self.handle_query_message(message_value) {
+                return Some(kind);
             }
-            return false;
+            if self.handle_pre_vote_message(message_value) {
+                return Some(InboundKind::RaftMessage);
+            }
+            return None;
         };
 
         self.handle_raft_message(message_value, message);
-        true
+        Some(InboundKind::RaftMessage)
+    }
+
+    /// Classifies a line received from a custom transport without handling it
+    ///
+    /// The payloads of `Action::Broadcast` and `Action::Send` are classified as one of
+    /// `InboundKind`. Lines that are not valid UTF-8 or JSON, or that are not understood by
+    /// the node, result in an error.
+    pub fn decode_inbound(&self, line: &[u8]) -> crate::Result<InboundKind> {
+        let text = std::str::from_utf8(line)?;
+        let json = nojson::RawJson::parse(text)?;
+        let value = json.value();
+        if crate::conv::json_to_message_with_limits(value, self.message_limits).is_ok() {
+            return Ok(InboundKind::RaftMessage);
+        }
+        if let Ok(command) = Command::try_from(value) {
+            return Ok(InboundKind::RedirectedCommand(command.proposal_id()));
+        }
+        if PreVoteMessage::try_from(value).is_ok() {
+            return Ok(InboundKind::RaftMessage);
+        }
+        match QueryMessage::try_from(value)? {
+            QueryMessage::Redirect { .. } => Ok(InboundKind::QueryRedirect),
+            QueryMessage::Proposed { .. } => Ok(InboundKind::QueryProposed),
+        }
     }
 
     fn handle_raft_message(
@@ -274,51 +1113,112 @@ impl Node {
         message: noraft::Message,
     ) {
         self.initialize_if_needed();
+        if self.is_duplicate_reply(&message) {
+            return;
+        }
         self.inner.handle_message(&message);
         self.maybe_emit_role_events();
 
+        // Calls from a stale leader are rejected, so only those of the current term count
+        if let noraft::Message::AppendEntriesCall { term, .. } = &message
+            && *term == self.inner.current_term()
+        {
+            self.last_leader_contact = Some(self.clock.now());
+            self.pre_votes = None;
+        }
+
+        if let noraft::Message::AppendEntriesReply {
+            from,
+            term,
+            last_position,
+            ..
+        } = &message
+            && self.is_leader()
+            && *term == self.inner.current_term()
+        {
+            let from = NodeId::from_inner(*from);
+            let now = self.clock.now();
+            self.follower_acks.insert(from, now);
+
+            // The follower's log matches ours up to `last_position` only if we have the same entry
+            if self.inner.log().entries().contains(*last_position) {
+                let match_index = self
+                    .follower_match_indices
+                    .entry(from)
+                    .or_insert(noraft::LogIndex::ZERO);
+                *match_index = (*match_index).max(last_position.index);
+            }
+        }
+
         let command_values = crate::conv::get_command_values(message_value, &message);
         for (pos, command) in command_values.into_iter().flatten() {
             if self.inner.log().entries().contains(pos) {
-                self.recent_commands.insert(pos.index, command);
+                self.insert_recent_command(pos, command);
             }
         }
     }
 
-    fn handle_redirected_command(&mut self, message_value: nojson::RawJsonValue<'_, '_>) -> bool {
-        if let Ok(command) = Command::try_from(message_value) {
-            // This is a redirected command
-            //
-            // TODO: Add redirect count limit
-            self.propose(command);
-            true
-        } else {
-            false
+    fn is_duplicate_reply(&mut self, message: &noraft::Message) -> bool {
+        let noraft::Message::AppendEntriesReply { from, .. } = message else {
+            return false;
+        };
+        let from = NodeId::from_inner(*from);
+        let now = self.clock.now();
+        if let Some((last, received_at)) = self.recent_replies.get(&from)
+            && last == message
+            && now.saturating_duration_since(*received_at) < DUPLICATE_REPLY_WINDOW
+        {
+            return true;
         }
+        self.recent_replies.insert(from, (message.clone(), now));
+        false
     }
 
-    fn handle_query_message(&mut self, message_value: nojson::RawJsonValue<'_, '_>) -> bool {
-        if let Ok(message) = QueryMessage::try_from(message_value) {
-            match message {
-                QueryMessage::Redirect {
-                    from,
-                    proposal_id,
-                    request,
-                } => {
-                    self.propose_query_for_redirect(from, proposal_id, request);
-                }
-                QueryMessage::Proposed {
-                    proposal_id,
-                    position,
-                    request,
-                } => {
+    fn handle_redirected_command(
+        &mut self,
+        message_value: nojson::RawJsonValue<'_, '_>,
+    ) -> Option<InboundKind> {
+        let command = Command::try_from(message_value).ok()?;
+
+        // This is a redirected command
+        //
+        // TODO: Add redirect count limit
+        let proposal_id = command.proposal_id();
+
+        // The received text is proposed as is so that its bytes are preserved.
+        // Note that this is not cheaper than re-serializing `command`:
+        // `JsonValue::new()` still copies and parses the text.
+        let encoded = JsonValue::new(message_value);
+        self.propose_encoded(command, Some(encoded));
+        Some(InboundKind::RedirectedCommand(proposal_id))
+    }
+
+    fn handle_query_message(
+        &mut self,
+        message_value: nojson::RawJsonValue<'_, '_>,
+    ) -> Option<InboundKind> {
+        let message = QueryMessage::try_from(message_value).ok()?;
+        match message {
+            QueryMessage::Redirect {
+                from,
+                proposal_id,
+                request,
+            } => {
+                self.propose_query_for_redirect(from, proposal_id, request);
+                Some(InboundKind::QueryRedirect)
+            }
+            QueryMessage::Proposed {
+                proposal_id,
+                position,
+                request,
+            } => {
+                // The query may have been dropped (e.g., its deadline passed) while redirected
+                if self.query_timings.contains_key(&proposal_id) {
                     self.pending_queries
                         .insert((position, proposal_id), request);
                 }
+                Some(InboundKind::QueryProposed)
             }
-            true
-        } else {
-            false
         }
     }
 
@@ -333,17 +1233,117 @@ impl Node {
             return None;
         }
 
+        #[cfg(debug_assertions)]
+        self.assert_invariants();
+
+        self.flush_pending_proposals();
         self.maybe_heartbeat_on_leader();
 
         let mut after_commit_actions = Vec::new();
         self.process_inner_actions(&mut after_commit_actions);
         self.emit_commit_actions();
+        self.expire_queries();
         self.emit_query_actions();
+        self.emit_token_query_actions();
         self.enqueue_after_commit_actions(after_commit_actions);
 
         self.action_queue.pop_front()
     }
 
+    /// Panics if the internal invariants of this node are violated
+    ///
+    /// The following conditions are checked:
+    /// - `applied_index() <= commit_index() <=` the last log index
+    /// - Every command entry in the pending raft messages and log appends is present in
+    ///   `recent_commands()` (otherwise `next_action()` cannot serialize them)
+    ///
+    /// This is also called at the beginning of `next_action()` when `debug_assertions` is enabled.
+    pub fn assert_invariants(&self) {
+        let commit_index = self.inner.commit_index();
+        let last_index = self.inner.log().entries().last_position().index;
+        assert!(
+            self.applied_index <= commit_index,
+            "applied index {} exceeds commit index {}",
+            self.applied_index.get(),
+            commit_index.get()
+        );
+        assert!(
+            commit_index <= last_index,
+            "commit index {} exceeds last log index {}",
+            commit_index.get(),
+            last_index.get()
+        );
+
+        let actions = self.inner.actions();
+        let message_entries = actions
+            .broadcast_message
+            .iter()
+            .chain(actions.send_messages.values())
+            .filter_map(|message| match message {
+                noraft::Message::AppendEntriesCall { entries, .. } => Some(entries),
+                _ => None,
+            });
+        for entries in message_entries.chain(actions.append_log_entries.iter()) {
+            for (pos, entry) in entries.iter_with_positions() {
+                if matches!(entry, noraft::LogEntry::Command) {
+                    assert!(
+                        self.recent_commands.contains_key(&pos.index),
+                        "command at index {} is pending to be sent but missing from recent commands",
+                        pos.index.get()
+                    );
+                }
+            }
+        }
+    }
+
+    /// Returns the queued actions that can be persisted and replayed after a restart
+    ///
+    /// Actions that are not replayable (see `Action::is_replayable()`) are skipped.
+    /// The queue itself is left untouched.
+    pub fn export_pending_actions(&self) -> Vec<JsonValue> {
+        self.action_queue
+            .iter()
+            .filter(|action| action.is_replayable())
+            .map(JsonValue::new)
+            .collect()
+    }
+
+    /// Appends the actions exported by `export_pending_actions()` to the action queue
+    ///
+    /// This is typically called right after `load()` so that undelivered messages are resent.
+    /// Nothing is imported if any of the actions cannot be decoded.
+    pub fn import_pending_actions(&mut self, actions: &[JsonValue]) -> Result<(), DecodeError> {
+        let actions = actions
+            .iter()
+            .map(|action| Action::try_from(action.get()))
+            .collect::<Result<Vec<_>, _>>()?;
+        self.action_queue.extend(actions);
+        Ok(())
+    }
+
+    /// Same as `next_action()`, but applies `Action::Apply` to `machine` and creates
+    /// the snapshot for `Action::SendSnapshot` from `StateMachine::snapshot()`
+    pub fn drive<M: StateMachine>(&mut self, machine: &mut M) -> Option<DriveOutput> {
+        let output = match self.next_action()? {
+            Action::Apply(apply) => {
+                let result = if apply.is_query() {
+                    machine.query(apply.request())
+                } else {
+                    machine.apply(apply.request())
+                };
+                DriveOutput::Applied { apply, result }
+            }
+            Action::SendSnapshot(dst) => {
+                match self.create_snapshot(self.applied_index, &machine.snapshot()) {
+                    Some(snapshot) => DriveOutput::SendSnapshot { dst, snapshot },
+                    None => DriveOutput::Action(Action::SendSnapshot(dst)),
+                }
+            }
+            action => DriveOutput::Action(action),
+        };
+        Some(output)
+    }
+
     fn maybe_heartbeat_on_leader(&mut self) {
         if self.applied_index < self.inner.commit_index() && self.is_leader() {
             // Invokes heartbeat to notify the new commit position to followers as fast as possible
@@ -366,6 +1366,8 @@ impl Node {
             to: NodeRole::from_inner(role),
         }));
         if role.is_leader() {
+            self.follower_acks.clear();
+            self.follower_match_indices.clear();
             self.push_action(Action::NotifyEvent(Event::BecameLeader {
                 term: self.inner.current_term(),
             }));
@@ -394,6 +1396,7 @@ impl Node {
                     self.push_action(Action::AppendStorageEntry(value));
                 }
                 noraft::Action::SendMessage(node_id, message) => {
+                    self.maybe_emit_vote_event(NodeId::from_inner(node_id), &message);
                     let message = self.encode_message(&message);
                     self.push_action(Action::Send(NodeId::from_inner(node_id), message));
                 }
@@ -404,11 +1407,29 @@ impl Node {
         }
     }
 
+    fn maybe_emit_vote_event(&mut self, to: NodeId, message: &noraft::Message) {
+        let noraft::Message::RequestVoteReply {
+            term, vote_granted, ..
+        } = message
+        else {
+            return;
+        };
+        let term = *term;
+        let event = if *vote_granted {
+            Event::VoteGranted { to, term }
+        } else {
+            Event::VoteDenied { to, term }
+        };
+        self.push_action(Action::NotifyEvent(event));
+    }
+
     fn handle_set_election_timeout(&mut self) {
+        self.timeout_deadline = Some(self.clock.now() + self.timeout_duration());
         self.push_action(Action::SetTimeout);
     }
 
     fn emit_commit_actions(&mut self) {
+        self.emit_proposal_committed_events();
         for i in self.applied_index.get()..self.inner.commit_index().get() {
             let index = noraft::LogIndex::new(i + 1);
 
@@ -420,9 +1441,15 @@ impl Node {
                 command.get_optional_member("proposal_id").expect("bug");
             let is_proposer = proposal_id
                 .as_ref()
-                .map(|id| id.is_proposer(self.id(), self.inner.generation().get()))
+                .map(|id| id.is_proposer(self.id(), self.generation()))
                 .unwrap_or(false);
 
+            let meta = command
+                .get()
+                .to_member("meta")
+                .ok()
+                .and_then(|m| m.get())
+                .map(JsonValue::new);
             let (source, request) =
                 match command.get_member::<String>("type").expect("bug").as_str() {
                     "Apply" => {
@@ -438,60 +1465,196 @@ impl Node {
                             .expect("bug");
                         (JsonValue::new(source_value), JsonValue::new(request_value))
                     }
-                    "Query" => continue,
+                    "Query" | "Noop" => continue,
+                    "RemoveNode" => {
+                        // Membership changes are proposed as configs, so this entry has no effect
+                        if let Some(proposal_id) = proposal_id.filter(|_| is_proposer) {
+                            self.proposals.remove(&proposal_id);
+                            self.push_action(Action::NotifyEvent(Event::ProposalDropped {
+                                proposal_id,
+                                reason: ProposalDropReason::RemoveNodeAsCommand,
+                            }));
+                        }
+                        continue;
+                    }
                     ty => panic!("bug: {ty}"),
                 };
 
-            self.push_action(Action::Apply(ApplyAction::new(
-                is_proposer,
-                index,
-                source,
-                request,
-            )));
+            // The same command can be committed more than once when a redirected command
+            // is forwarded again (e.g., across a leader change)
+            if let Some(proposal_id) = proposal_id
+                && !self.remember_applied_proposal(proposal_id)
+            {
+                self.push_action(Action::NotifyEvent(Event::DuplicateCommandIgnored {
+                    proposal_id,
+                    index,
+                }));
+                continue;
+            }
+
+            // Proposed by this node before its last restart: still applied, but nobody waits for it
+            let stale_generation = proposal_id
+                .is_some_and(|id| id.node_id() == self.id() && id.generation() < self.generation());
+
+            self.applied_user_index = index;
+            let apply = ApplyAction::new(is_proposer, index, source, request)
+                .with_meta(meta)
+                .with_stale_generation(stale_generation);
+            self.dispatch_apply(apply);
         }
         self.applied_index = self.inner.commit_index();
     }
 
+    /// Records `proposal_id` as applied, returning `false` if it has already been applied
+    ///
+    /// Only the most recent `MAX_APPLIED_PROPOSAL_IDS` ids are remembered. The window is
+    /// included in snapshots and the log after a snapshot is re-applied on `load()`, so every
+    /// replica detects the same duplicates at the same log index.
+    fn remember_applied_proposal(&mut self, proposal_id: ProposalId) -> bool {
+        if !self.applied_proposal_ids.insert(proposal_id) {
+            return false;
+        }
+        self.applied_proposal_order.push_back(proposal_id);
+        while self.applied_proposal_order.len() > MAX_APPLIED_PROPOSAL_IDS {
+            if let Some(old) = self.applied_proposal_order.pop_front() {
+                self.applied_proposal_ids.remove(&old);
+            }
+        }
+        true
+    }
+
+    fn emit_proposal_committed_events(&mut self) {
+        for i in self.applied_index.get()..self.inner.commit_index().get() {
+            let index = noraft::LogIndex::new(i + 1);
+            let Some(command) = self.recent_commands.get(&index) else {
+                continue;
+            };
+            let Ok(Some(proposal_id)) = command.get_optional_member::<ProposalId>("proposal_id")
+            else {
+                continue;
+            };
+            if !proposal_id.is_proposer(self.id(), self.generation())
+                || self.applied_proposal_ids.contains(&proposal_id)
+            {
+                continue;
+            }
+            self.push_action(Action::NotifyEvent(Event::ProposalCommitted {
+                proposal_id,
+                index,
+            }));
+        }
+    }
+
+    /// Drops the local queries whose deadline has passed, even if their position is not committed yet
+    fn expire_queries(&mut self) {
+        let now = self.clock.now();
+        let expired: Vec<ProposalId> = self
+            .query_timings
+            .iter()
+            .filter(|(_, (_, deadline))| deadline.is_some_and(|deadline| deadline < now))
+            .map(|(proposal_id, _)| *proposal_id)
+            .collect();
+        for proposal_id in expired {
+            self.query_timings.remove(&proposal_id);
+            self.drop_query(proposal_id, ProposalDropReason::QueryDeadlineExceeded);
+        }
+    }
+
+    fn drop_query(&mut self, proposal_id: ProposalId, reason: ProposalDropReason) {
+        self.pending_queries.retain(|(_, id), _| *id != proposal_id);
+        self.token_queries.retain(|(_, id), _| *id != proposal_id);
+        self.push_action(Action::NotifyEvent(Event::ProposalDropped {
+            proposal_id,
+            reason,
+        }));
+    }
+
     fn emit_query_actions(&mut self) {
         while let Some((&(position, _), _)) = self.pending_queries.first_key_value() {
             let status = self.inner.get_commit_status(position);
             match status {
                 noraft::CommitStatus::InProgress => break,
                 noraft::CommitStatus::Rejected | noraft::CommitStatus::Unknown => {
-                    let keys: Vec<_> = self
-                        .pending_queries
-                        .keys()
-                        .take_while(|(pos, _)| *pos == position)
-                        .cloned()
-                        .collect();
-                    for key in keys {
-                        self.pending_queries.remove(&key);
+                    for (proposal_id, _) in self.take_pending_queries_at(position) {
+                        self.query_timings.remove(&proposal_id);
                     }
                 }
                 noraft::CommitStatus::Committed => {
-                    let keys: Vec<_> = self
-                        .pending_queries
-                        .keys()
-                        .take_while(|(pos, _)| *pos == position)
-                        .cloned()
-                        .collect();
-                    for (position, proposal_id) in keys {
-                        let request = self
-                            .pending_queries
-                            .remove(&(position, proposal_id))
-                            .expect("pending_queries should have entry");
-                        self.push_action(Action::Apply(ApplyAction::new(
+                    // A single commit check resolves all the queries coalesced at `position`
+                    let now = self.clock.now();
+                    for (proposal_id, request) in self.take_pending_queries_at(position) {
+                        let mut apply = ApplyAction::new(
                             true,
                             position.index,
                             JsonValue::new(self.id()),
                             request,
-                        )));
+                        )
+                        .into_query();
+                        if let Some((proposed_at, deadline)) =
+                            self.query_timings.remove(&proposal_id)
+                        {
+                            if deadline.is_some_and(|deadline| deadline < now) {
+                                self.push_action(Action::NotifyEvent(Event::ProposalDropped {
+                                    proposal_id,
+                                    reason: ProposalDropReason::QueryDeadlineExceeded,
+                                }));
+                                continue;
+                            }
+                            apply = apply
+                                .with_query_elapsed(now.saturating_duration_since(proposed_at));
+                        }
+                        self.dispatch_apply(apply);
                     }
                 }
             }
         }
     }
 
+    fn emit_token_query_actions(&mut self) {
+        while let Some(entry) = self.token_queries.first_entry() {
+            if entry.key().0 > self.applied_index {
+                break;
+            }
+            let ((_, proposal_id), request) = entry.remove_entry();
+            let mut apply =
+                ApplyAction::new(true, self.applied_index, JsonValue::new(self.id()), request)
+                    .into_query();
+            if let Some((proposed_at, _)) = self.query_timings.remove(&proposal_id) {
+                apply = apply
+                    .with_query_elapsed(self.clock.now().saturating_duration_since(proposed_at));
+            }
+            self.dispatch_apply(apply);
+        }
+    }
+
+    fn take_pending_queries_at(
+        &mut self,
+        position: noraft::LogPosition,
+    ) -> Vec<(ProposalId, JsonValue)> {
+        let keys: Vec<_> = self
+            .pending_queries
+            .keys()
+            .take_while(|(pos, _)| *pos == position)
+            .cloned()
+            .collect();
+        keys.into_iter()
+            .map(|key| {
+                let request = self
+                    .pending_queries
+                    .remove(&key)
+                    .expect("pending_queries should have entry");
+                (key.1, request)
+            })
+            .collect()
+    }
+
+    fn dispatch_apply(&mut self, apply: ApplyAction) {
+        if self.apply_handler.call(&apply) {
+            return;
+        }
+        self.push_action(Action::Apply(apply));
+    }
+
     fn enqueue_after_commit_actions(&mut self, actions: Vec<Action>) {
         for action in actions {
             // TODO: Should use separate queue (set of dst?)
@@ -505,9 +1668,11 @@ impl Node {
     }
 
     fn encode_message(&self, message: &noraft::Message) -> JsonValue {
-        JsonValue::new(nojson::json(|f| {
-            crate::conv::fmt_message(f, message, &self.recent_commands)
-        }))
+        crate::conv::message_to_json_with_limits(
+            message,
+            &self.recent_commands,
+            self.message_limits,
+        )
     }
 
     fn encode_log_entries(&self, entries: &noraft::LogEntries) -> JsonValue {
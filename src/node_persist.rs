@@ -1,12 +1,56 @@
+use crate::machine::StateMachine;
 use crate::node_core::Node;
-use crate::node_types::{Action, JsonValue, RecentCommands, StorageEntry};
+use crate::node_types::{
+    Action, JsonValue, NodeId, ProposalId, RecentCommands, STORAGE_FORMAT_VERSION, SnapshotMeta,
+    StorageEntry, check_format_version,
+};
+
+/// Snapshot members covered by the `"checksum"` member, in the order they are hashed
+// TODO: "user_machine" の名前は改善する
+const SNAPSHOT_CHECKSUM_MEMBERS: [&str; 5] = [
+    "position",
+    "node_state",
+    "config",
+    "user_machine",
+    "log_entries",
+];
+
+/// Computes the FNV-1a (64-bit) checksum over the raw JSON texts of the snapshot members
+fn snapshot_checksum<'a>(member_texts: impl IntoIterator<Item = &'a str>) -> String {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for text in member_texts {
+        // The separator keeps member boundaries significant
+        for b in text.bytes().chain(std::iter::once(b'\n')) {
+            hash ^= u64::from(b);
+            hash = hash.wrapping_mul(0x100000001b3);
+        }
+    }
+    format!("{hash:016x}")
+}
 
 impl Node {
-    fn parse_snapshot_json(
+    pub(crate) fn parse_snapshot_json(
         snapshot: &JsonValue,
     ) -> Result<(noraft::LogPosition, noraft::ClusterConfig), nojson::JsonParseError> {
         let snapshot_json = snapshot.get();
 
+        // Verify checksum (snapshots written by older versions have no checksum member)
+        let checksum: Option<String> = snapshot_json.to_member("checksum")?.try_into()?;
+        if let Some(expected) = checksum {
+            let texts = SNAPSHOT_CHECKSUM_MEMBERS
+                .iter()
+                .map(|name| {
+                    snapshot_json
+                        .to_member(name)?
+                        .required()
+                        .map(|v| v.as_raw_str())
+                })
+                .collect::<Result<Vec<_>, nojson::JsonParseError>>()?;
+            if snapshot_checksum(texts) != expected {
+                return Err(snapshot_json.invalid("snapshot checksum mismatch"));
+            }
+        }
+
         // Extract position
         let position_json = snapshot_json.to_member("position")?.required()?;
         let position_term =
@@ -34,6 +78,15 @@ impl Node {
             config.new_voters.insert(noraft::NodeId::new(id));
         }
 
+        // Snapshots written by older versions have no "non_voters" member
+        let non_voters_json = config_json.to_member("non_voters")?;
+        if let Some(non_voters_json) = non_voters_json.get() {
+            for non_voter_id in non_voters_json.to_array()? {
+                let id: u64 = non_voter_id.try_into()?;
+                config.non_voters.insert(noraft::NodeId::new(id));
+            }
+        }
+
         Ok((position, config))
     }
 
@@ -41,188 +94,85 @@ impl Node {
         &mut self,
         entries: &'a [JsonValue],
     ) -> (bool, Option<nojson::RawJsonValue<'a, 'a>>) {
-        struct LoadState<'a> {
-            current_term: noraft::Term,
-            voted_for: Option<noraft::NodeId>,
-            config: noraft::ClusterConfig,
-            log_entries: noraft::LogEntries,
-            recent_commands: RecentCommands,
-            applied_index: noraft::LogIndex,
-            last_generation: u64,
-            user_machine: Option<nojson::RawJsonValue<'a, 'a>>,
+        let mut state = LoadState::new();
+        let mut snapshot = None;
+        for entry in entries {
+            match state.handle_entry(entry) {
+                Ok(true) => snapshot = Some(entry),
+                Ok(false) => {}
+                Err(_) => return (false, None),
+            }
         }
+        self.restore_loaded_state(state);
 
-        fn parse_log_entry(
-            entry_value: nojson::RawJsonValue<'_, '_>,
-            log_entries: &mut noraft::LogEntries,
-            recent_commands: &mut RecentCommands,
-            config: &mut noraft::ClusterConfig,
-        ) -> Result<(), nojson::JsonParseError> {
-            let entry_type = entry_value.to_member("type")?.required()?.as_string_str()?;
-
-            let log_entry = match entry_type {
-                "Term" => {
-                    let term =
-                        noraft::Term::new(entry_value.to_member("term")?.required()?.try_into()?);
-                    noraft::LogEntry::Term(term)
-                }
-                "ClusterConfig" => {
-                    let mut cfg = noraft::ClusterConfig::new();
-                    cfg.voters = entry_value
-                        .to_member("voters")?
-                        .required()?
-                        .to_array()?
-                        .map(|v| {
-                            let node_id: u64 = v.try_into()?;
-                            Ok(noraft::NodeId::new(node_id))
-                        })
-                        .collect::<Result<_, nojson::JsonParseError>>()?;
-
-                    cfg.new_voters = entry_value
-                        .to_member("new_voters")?
-                        .required()?
-                        .to_array()?
-                        .map(|v| {
-                            let node_id: u64 = v.try_into()?;
-                            Ok(noraft::NodeId::new(node_id))
-                        })
-                        .collect::<Result<_, nojson::JsonParseError>>()?;
-
-                    *config = cfg.clone();
-                    noraft::LogEntry::ClusterConfig(cfg)
-                }
-                "Command" => {
-                    let command_json = entry_value.to_member("value")?.required()?;
-                    let command = JsonValue::new(command_json);
-
-                    let current_index = noraft::LogIndex::new(
-                        log_entries.prev_position().index.get() + log_entries.len() as u64 + 1,
-                    );
-                    recent_commands.insert(current_index, command);
-
-                    noraft::LogEntry::Command
-                }
-                _ => {
-                    return Err(entry_value.invalid(format!("unknown entry type: {entry_type}")));
-                }
-            };
+        // The presence of "user_machine" has already been checked by `handle_entry()`
+        let user_machine = snapshot.and_then(|entry| {
+            let member = entry.get().to_member("user_machine").ok()?;
+            member.required().ok()
+        });
+        (true, user_machine)
+    }
 
-            log_entries.push(log_entry);
-            Ok(())
+    /// Same as `load()`, but consumes the entries one by one and returns an owned user machine
+    ///
+    /// Unlike `load()`, this does not require all the entries to be materialized at once,
+    /// so it is suitable for streaming entries directly from storage.
+    pub fn load_iter<I>(&mut self, entries: I) -> (bool, Option<JsonValue>)
+    where
+        I: IntoIterator<Item = JsonValue>,
+    {
+        match self.try_load_iter(entries) {
+            Ok(user_machine) => (true, user_machine),
+            Err(_) => (false, None),
         }
+    }
 
-        let result: Result<LoadState<'a>, nojson::JsonParseError> = (|| {
-            let mut last_generation: u64 = 0;
-            let mut current_term = noraft::Term::new(0);
-            let mut voted_for = None;
-            let mut config = noraft::ClusterConfig::new();
-            let mut log_entries = noraft::LogEntries::new(noraft::LogPosition::ZERO);
-            let mut recent_commands = std::collections::BTreeMap::new();
-            let mut applied_index = noraft::LogIndex::ZERO;
-            let mut user_machine = None;
-            let mut snapshot_loaded = false;
-
-            for entry in entries {
-                let ty = entry
-                    .get()
-                    .to_member("type")
-                    .ok()
-                    .and_then(|t| t.required().ok())
-                    .and_then(|t| t.to_unquoted_string_str().ok());
-                let Some(ty) = ty else {
-                    continue;
-                };
-
-                match ty.as_ref() {
-                    "InstallSnapshotRpc" => {
-                        let (position, snap_config) = Self::parse_snapshot_json(entry)?;
-                        config = snap_config;
-                        log_entries = noraft::LogEntries::new(position);
-                        recent_commands = std::collections::BTreeMap::new();
-                        applied_index = position.index;
-                        snapshot_loaded = true;
-
-                        let node_state = entry.get().to_member("node_state")?.required()?;
-                        let term = noraft::Term::new(
-                            node_state.to_member("term")?.required()?.try_into()?,
-                        );
-                        let voted_for_value: Option<u64> =
-                            node_state.to_member("voted_for")?.try_into()?;
-                        current_term = term;
-                        voted_for = voted_for_value.map(noraft::NodeId::new);
-
-                        let user_machine_value =
-                            entry.get().to_member("user_machine")?.required()?;
-                        user_machine = Some(user_machine_value);
-
-                        let entries_array = entry
-                            .get()
-                            .to_member("log_entries")?
-                            .required()?
-                            .to_array()?;
-                        for entry_value in entries_array {
-                            parse_log_entry(
-                                entry_value,
-                                &mut log_entries,
-                                &mut recent_commands,
-                                &mut config,
-                            )?;
-                        }
-                    }
-                    "NodeGeneration" => {
-                        last_generation = entry.get_member("generation")?;
-                    }
-                    "Term" => {
-                        current_term = noraft::Term::new(entry.get_member("term")?);
-                    }
-                    "VotedFor" => {
-                        let node_id: Option<u64> = entry.get().to_member("node_id")?.try_into()?;
-                        voted_for = node_id.map(noraft::NodeId::new);
-                    }
-                    "LogEntries" => {
-                        let prev_term = noraft::Term::new(entry.get_member("term")?);
-                        let prev_index = noraft::LogIndex::new(entry.get_member("index")?);
-                        if !snapshot_loaded && log_entries.is_empty() {
-                            log_entries = noraft::LogEntries::new(noraft::LogPosition {
-                                term: prev_term,
-                                index: prev_index,
-                            });
-                        }
-
-                        let entries_array =
-                            entry.get().to_member("entries")?.required()?.to_array()?;
-                        for entry_value in entries_array {
-                            parse_log_entry(
-                                entry_value,
-                                &mut log_entries,
-                                &mut recent_commands,
-                                &mut config,
-                            )?;
-                        }
-                    }
-                    _ => {}
-                }
+    /// Same as `load_iter()`, but takes the entries split into segments (e.g., one per storage file)
+    ///
+    /// The segments are loaded in order as if they were concatenated. A snapshot in a later
+    /// segment discards the log state restored from the preceding segments.
+    pub fn load_segments<I>(&mut self, segments: I) -> (bool, Option<JsonValue>)
+    where
+        I: IntoIterator<Item = Vec<JsonValue>>,
+    {
+        self.load_iter(segments.into_iter().flatten())
+    }
+
+    /// Same as `load_iter()`, but reports why the entries could not be loaded
+    pub(crate) fn try_load_iter<I>(
+        &mut self,
+        entries: I,
+    ) -> Result<Option<JsonValue>, nojson::JsonParseError>
+    where
+        I: IntoIterator<Item = JsonValue>,
+    {
+        let mut state = LoadState::new();
+        let mut snapshot = None;
+        for entry in entries {
+            if state.handle_entry(&entry)? {
+                snapshot = Some(entry);
             }
+        }
+        self.restore_loaded_state(state);
 
-            Ok(LoadState {
-                current_term,
-                voted_for,
-                config,
-                log_entries,
-                recent_commands,
-                applied_index,
-                last_generation,
-                user_machine,
-            })
-        })();
-
-        let state = match result {
-            Ok(state) => state,
-            Err(_) => return (false, None),
-        };
+        let user_machine = snapshot.and_then(|entry| {
+            let member = entry.get().to_member("user_machine").ok()?;
+            member.required().ok().map(JsonValue::new)
+        });
+        Ok(user_machine)
+    }
 
+    fn restore_loaded_state(&mut self, state: LoadState) {
         let log = noraft::Log::new(state.config.clone(), state.log_entries);
-        let new_generation = state.last_generation.saturating_add(1);
+        // The generation of a snapshot created by this node survives the truncation of the
+        // `NodeGeneration` entries preceding it, so that proposal IDs are never reused
+        let mut last_generation = state.last_generation;
+        if let Some((node_id, generation)) = state.snapshot_generation
+            && node_id == self.id()
+        {
+            last_generation = last_generation.max(generation);
+        }
+        let new_generation = last_generation.saturating_add(1);
         let generation = noraft::NodeGeneration::new(new_generation);
         self.inner = noraft::Node::restart(
             self.inner.id(),
@@ -232,17 +182,58 @@ impl Node {
             log,
         );
         self.last_role = self.inner.role();
-        self.recent_commands = state.recent_commands;
+        self.reset_recent_commands(state.recent_commands);
         self.applied_index = state.applied_index;
+        self.applied_user_index = state.applied_index;
         self.initialized = !state.config.voters.is_empty() || !state.config.new_voters.is_empty();
         self.pending_queries = std::collections::BTreeMap::new();
+        self.token_queries = std::collections::BTreeMap::new();
+        self.query_timings = std::collections::BTreeMap::new();
+        self.proposals = std::collections::BTreeMap::new();
+        self.pending_proposals = std::collections::VecDeque::new();
+        self.recent_replies = std::collections::BTreeMap::new();
+        self.follower_match_indices = std::collections::BTreeMap::new();
+        self.last_leader_contact = None;
+        self.pre_votes = None;
+        self.applied_proposal_ids = std::collections::BTreeSet::new();
+        self.applied_proposal_order = std::collections::VecDeque::new();
+        for proposal_id in state.applied_proposal_ids {
+            self.remember_applied_proposal(proposal_id);
+        }
+        self.pending_snapshot_index = None;
+        self.timeout_deadline = None;
         self.local_command_seqno = 0;
 
         let entry = StorageEntry::NodeGeneration(new_generation);
         let value = JsonValue::new(entry);
         self.push_action(Action::AppendStorageEntry(value));
+    }
 
-        (true, state.user_machine)
+    /// Same as `load()`, but also converts the user machine in the snapshot (if any) into `M`
+    ///
+    /// Note that the node state has already been restored when the conversion fails.
+    pub fn load_into<M>(&mut self, entries: &[JsonValue]) -> crate::Result<(bool, Option<M>)>
+    where
+        M: for<'a> TryFrom<nojson::RawJsonValue<'a, 'a>, Error = nojson::JsonParseError>,
+    {
+        let (ok, user_machine) = self.load(entries);
+        let user_machine = user_machine.map(M::try_from).transpose()?;
+        Ok((ok, user_machine))
+    }
+
+    /// Same as `load()`, but restores `machine` from the user machine in the snapshot (if any)
+    ///
+    /// Entries loaded after the snapshot are re-applied via `drive()` as usual.
+    pub fn load_machine<M: StateMachine>(
+        &mut self,
+        entries: &[JsonValue],
+        machine: &mut M,
+    ) -> crate::Result<bool> {
+        let (ok, user_machine) = self.load(entries);
+        if let Some(user_machine) = user_machine {
+            machine.restore(user_machine)?;
+        }
+        Ok(ok)
     }
 
     pub fn create_snapshot<T: nojson::DisplayJson>(
@@ -256,58 +247,328 @@ impl Node {
         }
 
         let (position, config) = self.inner.log().get_position_and_config(i).expect("bug");
-        let json = nojson::object(|f| {
-            f.member("type", "InstallSnapshotRpc")?;
-            f.member("from", self.id().get())?;
+        // TODO: Add utility funs
+        let position_json = JsonValue::new(nojson::object(|f| {
+            crate::conv::fmt_log_position_members(f, position)
+        }));
+        let node_state_json = JsonValue::new(nojson::object(|f| {
+            f.member("node_id", self.id().get())?;
             f.member("term", self.inner.current_term().get())?;
-            // TODO: Add utility funs
+            f.member("voted_for", self.inner.voted_for().map(|id| id.get()))?;
+            f.member("generation", self.generation())?;
+
+            // Every replica must skip the same duplicates, so the window travels with the snapshot
             f.member(
-                "position",
-                nojson::object(|f| crate::conv::fmt_log_position_members(f, position)),
-            )?;
+                "applied_proposal_ids",
+                nojson::array(|f| f.elements(self.applied_proposal_order.iter().copied())),
+            )
+        }));
+        let config_json = JsonValue::new(nojson::object(|f| {
             f.member(
-                "node_state",
-                nojson::object(|f| {
-                    f.member("node_id", self.id().get())?;
-                    f.member("term", self.inner.current_term().get())?;
-                    f.member("voted_for", self.inner.voted_for().map(|id| id.get()))
-                }),
+                "voters",
+                nojson::array(|f| f.elements(config.voters.iter().map(|v| v.get()))),
             )?;
             f.member(
-                "config",
-                nojson::object(|f| {
-                    f.member(
-                        "voters",
-                        nojson::array(|f| f.elements(config.voters.iter().map(|v| v.get()))),
-                    )?;
-                    f.member(
-                        "new_voters",
-                        nojson::array(|f| f.elements(config.new_voters.iter().map(|v| v.get()))),
-                    )
-                }),
+                "new_voters",
+                nojson::array(|f| f.elements(config.new_voters.iter().map(|v| v.get()))),
             )?;
-            f.member("user_machine", machine)?; // TODO: "user_machine" の名前は改善する
             f.member(
-                "log_entries",
-                nojson::array(|f| {
-                    for (pos, entry) in self.inner.log().entries().iter_with_positions() {
-                        if pos.index <= applied_index {
-                            continue;
-                        }
-                        f.element(nojson::object(|f| {
-                            crate::conv::fmt_log_entry_members(
-                                f,
-                                pos,
-                                &entry,
-                                &self.recent_commands,
-                            )
-                        }))?;
-                    }
-                    Ok(())
-                }),
+                "non_voters",
+                nojson::array(|f| f.elements(config.non_voters.iter().map(|v| v.get()))),
             )
+        }));
+        let user_machine_json = JsonValue::new(machine);
+        let log_entries_json = JsonValue::new(nojson::array(|f| {
+            for (pos, entry) in self.inner.log().entries().iter_with_positions() {
+                if pos.index <= applied_index {
+                    continue;
+                }
+                f.element(nojson::object(|f| {
+                    crate::conv::fmt_log_entry_members(f, pos, &entry, &self.recent_commands)
+                }))?;
+            }
+            Ok(())
+        }));
+        let members = [
+            &position_json,
+            &node_state_json,
+            &config_json,
+            &user_machine_json,
+            &log_entries_json,
+        ];
+        let checksum = snapshot_checksum(members.iter().map(|v| v.get().as_raw_str()));
+
+        let json = nojson::object(|f| {
+            f.member("type", "InstallSnapshotRpc")?;
+            f.member("v", STORAGE_FORMAT_VERSION)?;
+            f.member("from", self.id().get())?;
+            f.member("term", self.inner.current_term().get())?;
+            for (name, value) in SNAPSHOT_CHECKSUM_MEMBERS.iter().zip(members) {
+                f.member(name, value)?;
+            }
+            f.member("checksum", checksum.as_str())
         });
         let value = JsonValue::new(json);
         Some(value)
     }
+
+    /// Returns the position (term and index) of the snapshot that `create_snapshot()` would create now
+    ///
+    /// Returns `None` if a snapshot cannot be created (i.e., some committed entries are not applied yet).
+    pub fn snapshot_position(&self) -> Option<(noraft::Term, noraft::LogIndex)> {
+        let i = self.applied_index;
+        if i != self.inner.commit_index() {
+            return None;
+        }
+        let (position, _) = self.inner.log().get_position_and_config(i)?;
+        Some((position.term, position.index))
+    }
+
+    /// Same as `create_snapshot()`, but also returns a summary of the snapshot
+    ///
+    /// This avoids parsing the snapshot again for logging or transfer decisions.
+    pub fn create_snapshot_with_meta<T: nojson::DisplayJson>(
+        &self,
+        applied_index: noraft::LogIndex,
+        machine: &T,
+    ) -> Option<(JsonValue, SnapshotMeta)> {
+        let snapshot = self.create_snapshot(applied_index, machine)?;
+        let (position, config) = self
+            .inner
+            .log()
+            .get_position_and_config(applied_index)
+            .expect("bug");
+        let meta = SnapshotMeta {
+            term: position.term,
+            index: position.index,
+            byte_len: snapshot.byte_len(),
+            member_count: config.unique_nodes().count(),
+        };
+        Some((snapshot, meta))
+    }
+
+    /// Creates a snapshot if the number of in-memory commands reaches the snapshot threshold
+    ///
+    /// `user_machine` must reflect all `Action::Apply` emitted so far.
+    /// The memory log is not stripped by this method. After the returned snapshot has been
+    /// persisted, the caller must call `commit_snapshot()` with the `applied_index()` at the time
+    /// of the snapshot so that the log is never stripped before the snapshot is durable.
+    pub fn take_snapshot_if_needed<T: nojson::DisplayJson>(
+        &mut self,
+        user_machine: &T,
+    ) -> Option<JsonValue> {
+        let threshold = self.snapshot_threshold?;
+        if self.recent_commands.len() < threshold {
+            return None;
+        }
+
+        let index = self.applied_index;
+        let snapshot = self.create_snapshot(index, user_machine)?;
+        self.pending_snapshot_index = Some(index);
+        Some(snapshot)
+    }
+
+    /// Notifies that the snapshot returned by `take_snapshot_if_needed()` has been persisted
+    ///
+    /// Returns `false` if `index` does not correspond to the pending snapshot.
+    pub fn commit_snapshot(&mut self, index: noraft::LogIndex) -> bool {
+        if self.pending_snapshot_index != Some(index) {
+            return false;
+        }
+        self.pending_snapshot_index = None;
+        self.strip_memory_log(index)
+    }
+}
+
+/// Node state being reconstructed from storage entries by `Node::load()`
+struct LoadState {
+    current_term: noraft::Term,
+    voted_for: Option<noraft::NodeId>,
+    config: noraft::ClusterConfig,
+    log_entries: noraft::LogEntries,
+    recent_commands: RecentCommands,
+    applied_index: noraft::LogIndex,
+    last_generation: u64,
+    // Node ID and generation recorded in the last snapshot
+    snapshot_generation: Option<(NodeId, u64)>,
+    applied_proposal_ids: Vec<ProposalId>,
+    snapshot_loaded: bool,
+    boundary_seen: bool,
+}
+
+impl LoadState {
+    fn new() -> Self {
+        Self {
+            current_term: noraft::Term::new(0),
+            voted_for: None,
+            config: noraft::ClusterConfig::new(),
+            log_entries: noraft::LogEntries::new(noraft::LogPosition::ZERO),
+            recent_commands: std::collections::BTreeMap::new(),
+            applied_index: noraft::LogIndex::ZERO,
+            last_generation: 0,
+            snapshot_generation: None,
+            applied_proposal_ids: Vec::new(),
+            snapshot_loaded: false,
+            boundary_seen: false,
+        }
+    }
+
+    /// Applies a storage entry, returning `true` if the entry is a snapshot
+    fn handle_entry(&mut self, entry: &JsonValue) -> Result<bool, nojson::JsonParseError> {
+        check_format_version(entry)?;
+
+        let ty = entry
+            .get()
+            .to_member("type")
+            .ok()
+            .and_then(|t| t.required().ok())
+            .and_then(|t| t.to_unquoted_string_str().ok());
+        let Some(ty) = ty else {
+            return Ok(false);
+        };
+
+        match ty.as_ref() {
+            "InstallSnapshotRpc" => {
+                let (position, snap_config) = Node::parse_snapshot_json(entry)?;
+                self.config = snap_config;
+                self.log_entries = noraft::LogEntries::new(position);
+                self.recent_commands = std::collections::BTreeMap::new();
+                self.applied_index = position.index;
+                self.snapshot_loaded = true;
+
+                let node_state = entry.get().to_member("node_state")?.required()?;
+                let term = noraft::Term::new(node_state.to_member("term")?.required()?.try_into()?);
+                let voted_for_value: Option<u64> = node_state.to_member("voted_for")?.try_into()?;
+                self.current_term = term;
+                self.voted_for = voted_for_value.map(noraft::NodeId::new);
+
+                // Absent in snapshots created by older versions
+                let node_id: u64 = node_state.to_member("node_id")?.required()?.try_into()?;
+                let generation: Option<u64> = node_state.to_member("generation")?.try_into()?;
+                self.snapshot_generation = generation.map(|g| (NodeId::new(node_id), g));
+                self.applied_proposal_ids = Vec::new();
+                if let Some(ids) = node_state.to_member("applied_proposal_ids")?.get() {
+                    for id in ids.to_array()? {
+                        self.applied_proposal_ids.push(ProposalId::try_from(id)?);
+                    }
+                }
+
+                entry.get().to_member("user_machine")?.required()?;
+
+                let entries_array = entry
+                    .get()
+                    .to_member("log_entries")?
+                    .required()?
+                    .to_array()?;
+                for entry_value in entries_array {
+                    self.parse_log_entry(entry_value)?;
+                }
+                return Ok(true);
+            }
+            "NodeGeneration" => {
+                self.last_generation = entry.get_member("generation")?;
+            }
+            "SnapshotBoundary" => {
+                let index = noraft::LogIndex::new(entry.get_member("index")?);
+                if !self.snapshot_loaded || index != self.applied_index {
+                    return Err(entry.get().invalid(format!(
+                        "snapshot boundary at {} does not match the preceding snapshot",
+                        index.get()
+                    )));
+                }
+                self.boundary_seen = true;
+            }
+            "Term" => {
+                self.current_term = noraft::Term::new(entry.get_member("term")?);
+            }
+            "VotedFor" => {
+                let node_id: Option<u64> = entry.get().to_member("node_id")?.try_into()?;
+                self.voted_for = node_id.map(noraft::NodeId::new);
+            }
+            "LogEntries" => {
+                let prev_term = noraft::Term::new(entry.get_member("term")?);
+                let prev_index = noraft::LogIndex::new(entry.get_member("index")?);
+                let last_index =
+                    self.log_entries.prev_position().index.get() + self.log_entries.len() as u64;
+                if self.boundary_seen && prev_index.get() > last_index {
+                    return Err(entry.get().invalid(format!(
+                        "log entries after snapshot boundary are not contiguous: expected index <= {last_index}, got {}",
+                        prev_index.get()
+                    )));
+                }
+                if !self.snapshot_loaded && self.log_entries.is_empty() {
+                    self.log_entries = noraft::LogEntries::new(noraft::LogPosition {
+                        term: prev_term,
+                        index: prev_index,
+                    });
+                }
+
+                let entries_array = entry.get().to_member("entries")?.required()?.to_array()?;
+                for entry_value in entries_array {
+                    self.parse_log_entry(entry_value)?;
+                }
+            }
+            _ => {}
+        }
+        Ok(false)
+    }
+
+    fn parse_log_entry(
+        &mut self,
+        entry_value: nojson::RawJsonValue<'_, '_>,
+    ) -> Result<(), nojson::JsonParseError> {
+        let entry_type = entry_value.to_member("type")?.required()?.as_string_str()?;
+
+        let log_entry = match entry_type {
+            "Term" => {
+                let term =
+                    noraft::Term::new(entry_value.to_member("term")?.required()?.try_into()?);
+                noraft::LogEntry::Term(term)
+            }
+            "ClusterConfig" => {
+                let mut cfg = noraft::ClusterConfig::new();
+                cfg.voters = entry_value
+                    .to_member("voters")?
+                    .required()?
+                    .to_array()?
+                    .map(|v| {
+                        let node_id: u64 = v.try_into()?;
+                        Ok(noraft::NodeId::new(node_id))
+                    })
+                    .collect::<Result<_, nojson::JsonParseError>>()?;
+
+                cfg.new_voters = entry_value
+                    .to_member("new_voters")?
+                    .required()?
+                    .to_array()?
+                    .map(|v| {
+                        let node_id: u64 = v.try_into()?;
+                        Ok(noraft::NodeId::new(node_id))
+                    })
+                    .collect::<Result<_, nojson::JsonParseError>>()?;
+
+                self.config = cfg.clone();
+                noraft::LogEntry::ClusterConfig(cfg)
+            }
+            "Command" => {
+                let command_json = entry_value.to_member("value")?.required()?;
+                let command = JsonValue::new(command_json);
+
+                let current_index = noraft::LogIndex::new(
+                    self.log_entries.prev_position().index.get()
+                        + self.log_entries.len() as u64
+                        + 1,
+                );
+                self.recent_commands.insert(current_index, command);
+
+                noraft::LogEntry::Command
+            }
+            _ => {
+                return Err(entry_value.invalid(format!("unknown entry type: {entry_type}")));
+            }
+        };
+
+        self.log_entries.push(log_entry);
+        Ok(())
+    }
 }
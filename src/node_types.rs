@@ -1,3 +1,6 @@
+use crate::clock::{Clock, SystemClock};
+use crate::error::DecodeError;
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
 pub struct NodeId(noraft::NodeId);
 
@@ -6,8 +9,15 @@ impl NodeId {
         Self(noraft::NodeId::new(node_id))
     }
 
-    pub fn from_localhost_port(port: u16) -> Self {
-        Self::new(u64::from(port))
+    /// Makes a node id from the localhost port the node listens on
+    ///
+    /// Port 0 is rejected since it means "any port" when binding, so peers could not reach the node
+    /// by its id.
+    pub fn from_localhost_port(port: u16) -> crate::Result<Self> {
+        if port == 0 {
+            return Err(crate::Error::new("port 0 cannot be used as a node id"));
+        }
+        Ok(Self::new(u64::from(port)))
     }
 
     pub fn get(self) -> u64 {
@@ -15,15 +25,26 @@ impl NodeId {
     }
 
     pub fn to_localhost_addr(self) -> crate::Result<std::net::SocketAddr> {
-        let port = u16::try_from(self.get()).map_err(|_| {
-            crate::Error::new(format!(
-                "node id {} is out of localhost port range",
-                self.get()
-            ))
-        })?;
+        let port = u16::try_from(self.get())
+            .ok()
+            .filter(|port| *port != 0)
+            .ok_or_else(|| {
+                crate::Error::new(format!(
+                    "node id {} is out of localhost port range",
+                    self.get()
+                ))
+            })?;
         Ok(std::net::SocketAddr::from(([127, 0, 0, 1], port)))
     }
 
+    /// Resolves the network address of this node using `resolver`
+    pub fn to_addr<R: crate::Resolver + ?Sized>(
+        self,
+        resolver: &R,
+    ) -> crate::Result<std::net::SocketAddr> {
+        resolver.resolve(self)
+    }
+
     pub(crate) fn from_inner(node_id: noraft::NodeId) -> Self {
         Self(node_id)
     }
@@ -69,7 +90,7 @@ impl<'text, 'raw> TryFrom<nojson::RawJsonValue<'text, 'raw>> for NodeId {
 pub type RecentCommands = std::collections::BTreeMap<noraft::LogIndex, JsonValue>;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
-pub(crate) struct ProposalId {
+pub struct ProposalId {
     node_id: NodeId,
     generation: u64,
     local_seqno: u64,
@@ -84,6 +105,18 @@ impl ProposalId {
         }
     }
 
+    pub fn node_id(self) -> NodeId {
+        self.node_id
+    }
+
+    pub fn generation(self) -> u64 {
+        self.generation
+    }
+
+    pub fn local_seqno(self) -> u64 {
+        self.local_seqno
+    }
+
     pub(crate) fn is_proposer(&self, node_id: NodeId, generation: u64) -> bool {
         self.node_id == node_id && self.generation == generation
     }
@@ -108,20 +141,89 @@ impl<'text, 'raw> TryFrom<nojson::RawJsonValue<'text, 'raw>> for ProposalId {
     }
 }
 
+/// Session token identifying the log index at which a write was applied
+///
+/// Passing it to `Node::propose_local_query_after()` guarantees that the read observes the write,
+/// even on a lagging follower.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct CommitToken(pub noraft::LogIndex);
+
+impl nojson::DisplayJson for CommitToken {
+    fn fmt(&self, f: &mut nojson::JsonFormatter<'_, '_>) -> std::fmt::Result {
+        self.0.get().fmt(f)
+    }
+}
+
+impl<'text, 'raw> TryFrom<nojson::RawJsonValue<'text, 'raw>> for CommitToken {
+    type Error = nojson::JsonParseError;
+
+    fn try_from(value: nojson::RawJsonValue<'text, 'raw>) -> Result<Self, Self::Error> {
+        Ok(CommitToken(noraft::LogIndex::new(value.try_into()?)))
+    }
+}
+
+/// Result of `Node::propose_command_tracked()`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProposalOutcome {
+    pub id: ProposalId,
+    /// Log index assigned to the command (only when proposed directly on the leader)
+    ///
+    /// The command is applied at this index unless the entry is overwritten after a leader change,
+    /// so this is not a commit token: use `ApplyAction::commit_token()` once the command is applied.
+    pub assigned_index: Option<noraft::LogIndex>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProposalStatus {
+    Pending,
+    Committed(noraft::LogIndex),
+    NotFound,
+}
+
 #[derive(Clone, PartialEq, Eq)]
 pub struct JsonValue(std::sync::Arc<nojson::RawJsonOwned>);
 
 impl JsonValue {
+    /// Serializes `v` into a single-line JSON value
+    ///
+    /// If the serialized text spans multiple lines (e.g., a pre-serialized `RawJsonValue`
+    /// received from a client), the whitespace between tokens is removed so that the value
+    /// never corrupts line-based storage such as `FileStorage`.
     pub fn new<T: nojson::DisplayJson>(v: T) -> Self {
-        let line = nojson::Json(v).to_string();
+        let mut line = nojson::Json(v).to_string();
+        if line.contains('\n') {
+            line = compact_json(&line);
+        }
         let json = nojson::RawJsonOwned::parse(line).expect("infallible");
         Self(std::sync::Arc::new(json))
     }
 
+    /// Wraps already-parsed JSON without serializing and parsing it again
+    ///
+    /// Multi-line text is compacted into a single line (see `new()`).
+    pub fn from_raw(json: nojson::RawJsonOwned) -> Self {
+        if json.text().contains('\n') {
+            return Self::new(json.value());
+        }
+        Self(std::sync::Arc::new(json))
+    }
+
     pub fn get(&self) -> nojson::RawJsonValue<'_, '_> {
         self.0.value()
     }
 
+    pub fn byte_len(&self) -> usize {
+        self.0.text().len()
+    }
+
+    /// Converts this value into `T` (e.g., an application-defined command type)
+    pub fn parse_as<'a, T>(&'a self) -> Result<T, nojson::JsonParseError>
+    where
+        T: TryFrom<nojson::RawJsonValue<'a, 'a>, Error = nojson::JsonParseError>,
+    {
+        self.get().try_into()
+    }
+
     pub(crate) fn get_member<'a, T>(&'a self, name: &str) -> Result<T, nojson::JsonParseError>
     where
         T: TryFrom<nojson::RawJsonValue<'a, 'a>, Error = nojson::JsonParseError>,
@@ -140,6 +242,34 @@ impl JsonValue {
     }
 }
 
+/// Removes the whitespace between the tokens of valid JSON text
+///
+/// Raw newlines can only appear as whitespace (they must be escaped inside strings),
+/// so the result always fits in a single line.
+fn compact_json(text: &str) -> String {
+    let mut compacted = String::with_capacity(text.len());
+    let mut in_string = false;
+    let mut escaped = false;
+    for c in text.chars() {
+        if in_string {
+            compacted.push(c);
+            if escaped {
+                escaped = false;
+            } else if c == '\\' {
+                escaped = true;
+            } else if c == '"' {
+                in_string = false;
+            }
+        } else if c == '"' {
+            in_string = true;
+            compacted.push(c);
+        } else if !c.is_ascii_whitespace() {
+            compacted.push(c);
+        }
+    }
+    compacted
+}
+
 impl nojson::DisplayJson for JsonValue {
     fn fmt(&self, f: &mut nojson::JsonFormatter<'_, '_>) -> std::fmt::Result {
         write!(f.inner_mut(), "{}", self.0.text())
@@ -202,19 +332,16 @@ impl nojson::DisplayJson for QueryMessage {
 }
 
 impl<'text, 'raw> TryFrom<nojson::RawJsonValue<'text, 'raw>> for QueryMessage {
-    type Error = nojson::JsonParseError;
+    type Error = DecodeError;
 
     fn try_from(value: nojson::RawJsonValue<'text, 'raw>) -> Result<Self, Self::Error> {
-        let ty = value
-            .to_member("type")?
-            .required()?
-            .to_unquoted_string_str()?;
+        let decoder = Decoder::new("QueryMessage", value);
+        let ty = decoder.ty()?;
         match ty.as_ref() {
             "Redirect" => {
-                let from: u64 = value.to_member("from")?.required()?.try_into()?;
-                let proposal_id = value.to_member("proposal_id")?.required()?.try_into()?;
-                let request_json = value.to_member("request")?.required()?;
-                let request = JsonValue::new(request_json);
+                let from: u64 = decoder.member("from")?;
+                let proposal_id = decoder.member("proposal_id")?;
+                let request = JsonValue::new(decoder.raw_member("request")?);
                 Ok(QueryMessage::Redirect {
                     from: NodeId::new(from),
                     proposal_id,
@@ -222,19 +349,92 @@ impl<'text, 'raw> TryFrom<nojson::RawJsonValue<'text, 'raw>> for QueryMessage {
                 })
             }
             "Proposed" => {
-                let proposal_id = value.to_member("proposal_id")?.required()?.try_into()?;
-                let term = noraft::Term::new(value.to_member("term")?.required()?.try_into()?);
-                let index =
-                    noraft::LogIndex::new(value.to_member("index")?.required()?.try_into()?);
-                let request_json = value.to_member("request")?.required()?;
-                let request = JsonValue::new(request_json);
+                let proposal_id = decoder.member("proposal_id")?;
+                let term = noraft::Term::new(decoder.member("term")?);
+                let index = noraft::LogIndex::new(decoder.member("index")?);
+                let request = JsonValue::new(decoder.raw_member("request")?);
                 Ok(QueryMessage::Proposed {
                     proposal_id,
                     position: noraft::LogPosition { term, index },
                     request,
                 })
             }
-            ty => Err(value.invalid(format!("unknown query message type: {ty}"))),
+            ty => Err(decoder.unknown_type(ty)),
+        }
+    }
+}
+
+/// Pre-vote round messages exchanged before a node starts a real election
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum PreVoteMessage {
+    Call {
+        from: NodeId,
+        // Term the sender would use if it started an election
+        term: noraft::Term,
+        last_position: noraft::LogPosition,
+    },
+    Reply {
+        from: NodeId,
+        term: noraft::Term,
+        granted: bool,
+    },
+}
+
+impl nojson::DisplayJson for PreVoteMessage {
+    fn fmt(&self, f: &mut nojson::JsonFormatter<'_, '_>) -> std::fmt::Result {
+        match self {
+            PreVoteMessage::Call {
+                from,
+                term,
+                last_position,
+            } => f.object(|f| {
+                f.member("type", "PreVoteCall")?;
+                f.member("from", from.get())?;
+                f.member("term", term.get())?;
+                f.member("last_term", last_position.term.get())?;
+                f.member("last_index", last_position.index.get())
+            }),
+            PreVoteMessage::Reply {
+                from,
+                term,
+                granted,
+            } => f.object(|f| {
+                f.member("type", "PreVoteReply")?;
+                f.member("from", from.get())?;
+                f.member("term", term.get())?;
+                f.member("granted", granted)
+            }),
+        }
+    }
+}
+
+impl<'text, 'raw> TryFrom<nojson::RawJsonValue<'text, 'raw>> for PreVoteMessage {
+    type Error = DecodeError;
+
+    fn try_from(value: nojson::RawJsonValue<'text, 'raw>) -> Result<Self, Self::Error> {
+        let decoder = Decoder::new("PreVoteMessage", value);
+        let ty = decoder.ty()?;
+        let from = NodeId::new(decoder.member("from")?);
+        let term = noraft::Term::new(decoder.member("term")?);
+        match ty.as_ref() {
+            "PreVoteCall" => {
+                let last_term = noraft::Term::new(decoder.member("last_term")?);
+                let last_index = noraft::LogIndex::new(decoder.member("last_index")?);
+                Ok(PreVoteMessage::Call {
+                    from,
+                    term,
+                    last_position: noraft::LogPosition {
+                        term: last_term,
+                        index: last_index,
+                    },
+                })
+            }
+            "PreVoteReply" => Ok(PreVoteMessage::Reply {
+                from,
+                term,
+                granted: decoder.member("granted")?,
+            }),
+            ty => Err(decoder.unknown_type(ty)),
         }
     }
 }
@@ -245,8 +445,25 @@ pub(crate) enum Command {
         proposal_id: ProposalId,
         source: JsonValue,
         command: JsonValue,
+        meta: Option<JsonValue>,
     },
     Query,
+    Noop,
+    RemoveNode {
+        proposal_id: ProposalId,
+        node_id: NodeId,
+    },
+}
+
+impl Command {
+    pub(crate) fn proposal_id(&self) -> Option<ProposalId> {
+        match self {
+            Command::Apply { proposal_id, .. } | Command::RemoveNode { proposal_id, .. } => {
+                Some(*proposal_id)
+            }
+            Command::Query | Command::Noop => None,
+        }
+    }
 }
 
 impl nojson::DisplayJson for Command {
@@ -256,40 +473,61 @@ impl nojson::DisplayJson for Command {
                 proposal_id,
                 source,
                 command,
+                meta,
             } => f.object(|f| {
                 f.member("type", "Apply")?;
                 f.member("proposal_id", proposal_id)?;
                 f.member("source", source)?;
-                f.member("command", command)
+                f.member("command", command)?;
+                if let Some(meta) = meta {
+                    f.member("meta", meta)?;
+                }
+                Ok(())
             }),
             Command::Query => f.object(|f| f.member("type", "Query")),
+            Command::Noop => f.object(|f| f.member("type", "Noop")),
+            Command::RemoveNode {
+                proposal_id,
+                node_id,
+            } => f.object(|f| {
+                f.member("type", "RemoveNode")?;
+                f.member("proposal_id", proposal_id)?;
+                f.member("node_id", node_id)
+            }),
         }
     }
 }
 
 impl<'text, 'raw> TryFrom<nojson::RawJsonValue<'text, 'raw>> for Command {
-    type Error = nojson::JsonParseError;
+    type Error = DecodeError;
 
     fn try_from(value: nojson::RawJsonValue<'text, 'raw>) -> Result<Self, Self::Error> {
-        let ty = value
-            .to_member("type")?
-            .required()?
-            .to_unquoted_string_str()?;
+        let decoder = Decoder::new("Command", value);
+        let ty = decoder.ty()?;
         match ty.as_ref() {
             "Apply" => {
-                let proposal_id = value.to_member("proposal_id")?.required()?.try_into()?;
-                let source_json = value.to_member("source")?.required()?;
-                let source = JsonValue::new(source_json);
-                let command_json = value.to_member("command")?.required()?;
-                let command = JsonValue::new(command_json);
+                let proposal_id = decoder.member("proposal_id")?;
+                let source = JsonValue::new(decoder.raw_member("source")?);
+                let command = JsonValue::new(decoder.raw_member("command")?);
+                let meta = decoder.optional_raw_member("meta")?.map(JsonValue::new);
                 Ok(Command::Apply {
                     proposal_id,
                     source,
                     command,
+                    meta,
                 })
             }
             "Query" => Ok(Command::Query),
-            ty => Err(value.invalid(format!("unknown command type: {ty}"))),
+            "Noop" => Ok(Command::Noop),
+            "RemoveNode" => {
+                let proposal_id = decoder.member("proposal_id")?;
+                let node_id = decoder.member("node_id")?;
+                Ok(Command::RemoveNode {
+                    proposal_id,
+                    node_id,
+                })
+            }
+            ty => Err(decoder.unknown_type(ty)),
         }
     }
 }
@@ -300,6 +538,10 @@ pub struct ApplyAction {
     index: noraft::LogIndex,
     source: JsonValue,
     request: JsonValue,
+    query_elapsed: Option<std::time::Duration>,
+    meta: Option<JsonValue>,
+    stale_generation: bool,
+    is_query: bool,
 }
 
 impl ApplyAction {
@@ -314,26 +556,139 @@ impl ApplyAction {
             index,
             source,
             request,
+            query_elapsed: None,
+            meta: None,
+            stale_generation: false,
+            is_query: false,
         }
     }
 
+    pub(crate) fn with_meta(mut self, meta: Option<JsonValue>) -> Self {
+        self.meta = meta;
+        self
+    }
+
+    pub(crate) fn into_query(mut self) -> Self {
+        self.is_query = true;
+        self
+    }
+
+    pub(crate) fn with_stale_generation(mut self, stale_generation: bool) -> Self {
+        self.stale_generation = stale_generation;
+        self
+    }
+
+    pub(crate) fn with_query_elapsed(mut self, elapsed: std::time::Duration) -> Self {
+        self.query_elapsed = Some(elapsed);
+        self
+    }
+
     pub fn index(&self) -> noraft::LogIndex {
         self.index
     }
 
+    /// Returns the session token to hand back to the client for subsequent read-your-writes queries
+    pub fn commit_token(&self) -> CommitToken {
+        CommitToken(self.index)
+    }
+
     pub fn request(&self) -> nojson::RawJsonValue<'_, '_> {
         self.request.get()
     }
 
+    /// Returns the JSON text of the request exactly as it was proposed
+    ///
+    /// This is useful to forward the committed request verbatim (e.g., to an audit log).
+    pub fn request_bytes(&self) -> &str {
+        self.request.get().as_raw_str()
+    }
+
+    /// Returns the source of the command, only on the node that proposed it
+    ///
+    /// Every node applies the command, but only the proposer should answer the client.
+    /// Commands proposed via `ResponseRouter` can also be answered by checking the node ID
+    /// in `ResponseRouter::route()` against `Node::id()`.
     pub fn source(&self) -> Option<nojson::RawJsonValue<'_, '_>> {
         self.is_proposer.then(|| self.source.get())
     }
+
+    /// Returns the source regardless of whether this node is the proposer
+    pub(crate) fn raw_source(&self) -> nojson::RawJsonValue<'_, '_> {
+        self.source.get()
+    }
+
+    /// Returns how long the query waited on this node between its proposal and its resolution
+    ///
+    /// Returns `None` if this action is not the result of a query.
+    pub fn query_elapsed(&self) -> Option<std::time::Duration> {
+        self.query_elapsed
+    }
+
+    /// Returns `true` if this action resolves a query (rather than applying a committed command)
+    ///
+    /// Queries are only resolved on the proposer, so they must not modify the state machine.
+    pub fn is_query(&self) -> bool {
+        self.is_query
+    }
+
+    /// Returns `true` if this node proposed the command in a previous generation (i.e., before a restart)
+    ///
+    /// Such a command is applied as usual, but the client that issued it is no longer waiting
+    /// for the result on this node, so no response needs to be sent.
+    pub fn is_stale_generation(&self) -> bool {
+        self.stale_generation
+    }
+
+    /// Returns the metadata attached by `Node::propose_command_with_meta()`, if any
+    pub fn meta(&self) -> Option<nojson::RawJsonValue<'_, '_>> {
+        self.meta.as_ref().map(|meta| meta.get())
+    }
+}
+
+type ApplyHandlerFn = dyn 'static + Send + FnMut(&ApplyAction) -> bool;
+
+#[derive(Clone, Default)]
+pub(crate) struct ApplyHandler(Option<std::sync::Arc<std::sync::Mutex<ApplyHandlerFn>>>);
+
+impl ApplyHandler {
+    pub(crate) fn new<F>(handler: F) -> Self
+    where
+        F: 'static + Send + FnMut(&ApplyAction) -> bool,
+    {
+        Self(Some(std::sync::Arc::new(std::sync::Mutex::new(handler))))
+    }
+
+    pub(crate) fn call(&self, apply: &ApplyAction) -> bool {
+        let Some(handler) = &self.0 else {
+            return false;
+        };
+        let mut handler = handler.lock().unwrap_or_else(|e| e.into_inner());
+        (*handler)(apply)
+    }
+}
+
+impl std::fmt::Debug for ApplyHandler {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.0.is_some() {
+            write!(f, "ApplyHandler(..)")
+        } else {
+            write!(f, "ApplyHandler(None)")
+        }
+    }
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Action {
     SetTimeout,
     AppendStorageEntry(JsonValue),
+    /// Barrier indicating that all preceding `AppendStorageEntry` entries must be durable
+    /// before the subsequent actions are executed
+    ///
+    /// Raft requires the term, vote and log entries to be persisted before sending any message
+    /// that depends on them. Applications that batch or asynchronously write storage entries
+    /// must flush (fsync) them when this action is received.
+    /// Applications that persist each entry synchronously can ignore this action.
+    SyncStorage,
     Broadcast(JsonValue),
     Send(NodeId, JsonValue),
     SendSnapshot(NodeId),
@@ -341,6 +696,227 @@ pub enum Action {
     Apply(ApplyAction),
 }
 
+/// Output of `Node::drive()`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DriveOutput {
+    /// Action to be handled by the application (never `Action::Apply`)
+    Action(Action),
+    /// A command or query was applied to the state machine
+    ///
+    /// `result` is the value returned by `StateMachine::apply()` (or `StateMachine::query()`
+    /// for queries), which is typically
+    /// sent back to the client identified by `apply.source()`.
+    Applied {
+        apply: ApplyAction,
+        result: JsonValue,
+    },
+    /// Same as `Action::SendSnapshot`, but the snapshot has already been created from
+    /// `StateMachine::snapshot()`
+    SendSnapshot { dst: NodeId, snapshot: JsonValue },
+}
+
+/// Summary of a snapshot returned by `Node::create_snapshot_with_meta()`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SnapshotMeta {
+    /// Term of the last log entry covered by the snapshot
+    pub term: noraft::Term,
+    /// Index of the last log entry covered by the snapshot
+    pub index: noraft::LogIndex,
+    /// Length of the serialized snapshot in bytes
+    pub byte_len: usize,
+    /// Number of distinct members in the cluster configuration of the snapshot
+    pub member_count: usize,
+}
+
+/// Tuning knobs of a `Node` passed to `Node::start_with_config()`
+///
+/// Each knob (except the timeouts) can also be changed later via the corresponding `Node::set_*()` method.
+#[derive(Debug, Clone)]
+pub struct NodeConfig {
+    /// Interval between heartbeats sent by the leader (50ms by default)
+    pub heartbeat_interval: std::time::Duration,
+    /// Timeout after which a follower starts an election (150ms by default)
+    ///
+    /// Candidates add a small per-node jitter to this value.
+    pub election_timeout: std::time::Duration,
+    /// See `Node::set_snapshot_threshold()`
+    pub snapshot_threshold: Option<usize>,
+    /// See `Node::set_recent_commands_bytes_limit()`
+    pub recent_commands_bytes_limit: Option<usize>,
+    /// See `Node::set_max_pending_proposals()`
+    pub max_pending_proposals: usize,
+    /// See `Node::set_leader_lease()`
+    pub leader_lease: Option<std::time::Duration>,
+    /// See `Node::set_clock()`
+    pub clock: std::sync::Arc<dyn Clock>,
+    /// Limits on the size of raft messages accepted by `Node::handle_message()` (unlimited by default)
+    ///
+    /// The leader also splits the entries it sends to fit these limits,
+    /// and commands too large to be sent are dropped with `ProposalDropReason::CommandTooLarge`.
+    /// `Node::start_with_config()` panics if the limits are invalid.
+    pub message_limits: crate::conv::MessageLimits,
+    /// Whether an election timeout first runs a pre-vote round (disabled by default)
+    ///
+    /// With pre-vote, a node only increments its term once a majority of voters would vote for it.
+    /// This prevents a node that rejoins after a partition from forcing the stable leader to
+    /// step down.
+    pub pre_vote: bool,
+}
+
+impl Default for NodeConfig {
+    fn default() -> Self {
+        Self {
+            heartbeat_interval: std::time::Duration::from_millis(50),
+            election_timeout: std::time::Duration::from_millis(150),
+            snapshot_threshold: None,
+            recent_commands_bytes_limit: None,
+            max_pending_proposals: 1024,
+            leader_lease: None,
+            clock: std::sync::Arc::new(SystemClock),
+            message_limits: crate::conv::MessageLimits::UNLIMITED,
+            pre_vote: false,
+        }
+    }
+}
+
+/// Output of `Node::tick()`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Tick {
+    /// Actions to be handled by the application (never `Action::NotifyEvent`)
+    pub actions: Vec<Action>,
+    /// Events taken from `Action::NotifyEvent`
+    pub events: Vec<Event>,
+    /// Time at which `Node::tick()` should be called next at the latest
+    pub next_deadline: std::time::Instant,
+}
+
+/// Kind of a message passed to `Node::handle_message()`
+///
+/// Reported by `Node::decode_inbound()` and `Node::handle_message_classified()`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum InboundKind {
+    /// Raft protocol message (payload of `Action::Broadcast` or `Action::Send`)
+    RaftMessage,
+    /// Command redirected from a follower to the leader
+    ///
+    /// The proposal ID is `None` for internal commands that carry no ID.
+    RedirectedCommand(Option<ProposalId>),
+    /// Query redirected from a follower to the leader
+    QueryRedirect,
+    /// The leader's reply telling a follower the log position of its redirected query
+    QueryProposed,
+}
+
+impl Action {
+    /// Returns `true` if this action can be persisted and replayed after a restart
+    ///
+    /// `Action::Apply` is not replayable because committed commands are re-applied by `load()`,
+    /// and `Action::NotifyEvent` is only informational.
+    /// `Action::AppendStorageEntry` is not replayable either: appending the same entry twice
+    /// would duplicate it in storage, and an entry that was never appended may already be
+    /// superseded by the log the node recovered from.
+    /// The other actions are safe to replay: Raft tolerates duplicated and stale messages,
+    /// and `SetTimeout` merely re-arms the timer.
+    pub fn is_replayable(&self) -> bool {
+        !matches!(
+            self,
+            Action::Apply(_) | Action::NotifyEvent(_) | Action::AppendStorageEntry(_)
+        )
+    }
+}
+
+impl nojson::DisplayJson for Action {
+    fn fmt(&self, f: &mut nojson::JsonFormatter<'_, '_>) -> std::fmt::Result {
+        match self {
+            Action::SetTimeout => f.object(|f| f.member("type", "SetTimeout")),
+            Action::AppendStorageEntry(entry) => f.object(|f| {
+                f.member("type", "AppendStorageEntry")?;
+                f.member("entry", entry)
+            }),
+            Action::SyncStorage => f.object(|f| f.member("type", "SyncStorage")),
+            Action::Broadcast(message) => f.object(|f| {
+                f.member("type", "Broadcast")?;
+                f.member("message", message)
+            }),
+            Action::Send(dst, message) => f.object(|f| {
+                f.member("type", "Send")?;
+                f.member("dst", dst)?;
+                f.member("message", message)
+            }),
+            Action::SendSnapshot(dst) => f.object(|f| {
+                f.member("type", "SendSnapshot")?;
+                f.member("dst", dst)
+            }),
+            Action::NotifyEvent(event) => f.object(|f| {
+                f.member("type", "NotifyEvent")?;
+                f.member("event", event.to_string())
+            }),
+            Action::Apply(apply) => f.object(|f| {
+                f.member("type", "Apply")?;
+                f.member("index", apply.index.get())?;
+                f.member("request", &apply.request)?;
+                f.member("source", apply.source())?;
+                if let Some(meta) = &apply.meta {
+                    f.member("meta", meta)?;
+                }
+                Ok(())
+            }),
+        }
+    }
+}
+
+impl<'text, 'raw> TryFrom<nojson::RawJsonValue<'text, 'raw>> for Action {
+    type Error = DecodeError;
+
+    /// Decodes a replayable action (see `Action::is_replayable()`)
+    fn try_from(value: nojson::RawJsonValue<'text, 'raw>) -> Result<Self, Self::Error> {
+        let decoder = Decoder::new("Action", value);
+        let ty = decoder.ty()?;
+        match ty.as_ref() {
+            "SetTimeout" => Ok(Action::SetTimeout),
+            "SyncStorage" => Ok(Action::SyncStorage),
+            "Broadcast" => Ok(Action::Broadcast(JsonValue::new(
+                decoder.raw_member("message")?,
+            ))),
+            "Send" => {
+                let dst = decoder.member("dst")?;
+                let message = JsonValue::new(decoder.raw_member("message")?);
+                Ok(Action::Send(dst, message))
+            }
+            "SendSnapshot" => Ok(Action::SendSnapshot(decoder.member("dst")?)),
+            ty @ ("AppendStorageEntry" | "NotifyEvent" | "Apply") => {
+                Err(decoder.invalid("type", format!("{ty} action is not replayable")))
+            }
+            ty => Err(decoder.unknown_type(ty)),
+        }
+    }
+}
+
+/// Compact summary for logging (payloads are shown as their byte size)
+impl std::fmt::Display for Action {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Action::SetTimeout => write!(f, "SetTimeout"),
+            Action::AppendStorageEntry(entry) => {
+                write!(f, "AppendStorageEntry({} bytes)", entry.byte_len())
+            }
+            Action::SyncStorage => write!(f, "SyncStorage"),
+            Action::Broadcast(message) => write!(f, "Broadcast({} bytes)", message.byte_len()),
+            Action::Send(dst, message) => {
+                write!(f, "Send(node={dst}, {} bytes)", message.byte_len())
+            }
+            Action::SendSnapshot(dst) => write!(f, "SendSnapshot(node={dst})"),
+            Action::NotifyEvent(event) => write!(f, "NotifyEvent({event})"),
+            Action::Apply(apply) => write!(
+                f,
+                "Apply(index={}, {} bytes)",
+                apply.index().get(),
+                apply.request_bytes().len()
+            ),
+        }
+    }
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
 pub enum NodeRole {
     Follower,
@@ -360,8 +936,75 @@ impl NodeRole {
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Event {
-    RoleChanged { from: NodeRole, to: NodeRole },
-    BecameLeader { term: noraft::Term },
+    RoleChanged {
+        from: NodeRole,
+        to: NodeRole,
+    },
+    BecameLeader {
+        term: noraft::Term,
+    },
+    ElectionTimeout {
+        term: noraft::Term,
+    },
+    VoteGranted {
+        to: NodeId,
+        term: noraft::Term,
+    },
+    VoteDenied {
+        to: NodeId,
+        term: noraft::Term,
+    },
+    ProposalDropped {
+        proposal_id: ProposalId,
+        reason: ProposalDropReason,
+    },
+    SnapshotNeeded {
+        recent_commands_bytes: usize,
+    },
+    /// A command proposed by this node was committed by a majority
+    ///
+    /// This is emitted before the corresponding `Action::Apply`.
+    ProposalCommitted {
+        proposal_id: ProposalId,
+        index: noraft::LogIndex,
+    },
+    /// A committed command was not applied because a command with the same proposal ID was already applied
+    DuplicateCommandIgnored {
+        proposal_id: ProposalId,
+        index: noraft::LogIndex,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ProposalDropReason {
+    /// The leader could not confirm its leadership within the lease duration
+    LeaseExpired,
+    /// The query was not resolved before its deadline
+    QueryDeadlineExceeded,
+    /// The proposal was evicted from the buffer of proposals awaiting a known leader
+    PendingProposalsFull,
+    /// The query was evicted because too many queries were awaiting resolution
+    TooManyPendingQueries,
+    /// The query was proposed while no leader was known
+    LeaderUnknown,
+    /// The node removal was committed as a log command instead of a cluster config, so it was ignored
+    RemoveNodeAsCommand,
+    /// The command cannot be replicated within `NodeConfig::message_limits`
+    CommandTooLarge,
+}
+
+impl std::fmt::Display for ProposalDropReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ProposalDropReason::LeaseExpired => write!(f, "lease expired"),
+            ProposalDropReason::QueryDeadlineExceeded => write!(f, "query deadline exceeded"),
+            ProposalDropReason::PendingProposalsFull => write!(f, "pending proposals full"),
+            ProposalDropReason::TooManyPendingQueries => write!(f, "too many pending queries"),
+            ProposalDropReason::LeaderUnknown => write!(f, "leader unknown"),
+            ProposalDropReason::RemoveNodeAsCommand => write!(f, "remove node as command"),
+            ProposalDropReason::CommandTooLarge => write!(f, "command too large"),
+        }
+    }
 }
 
 impl std::fmt::Display for Event {
@@ -384,15 +1027,80 @@ impl std::fmt::Display for Event {
                 )
             }
             Event::BecameLeader { term } => write!(f, "became leader (term={})", term.get()),
+            Event::ElectionTimeout { term } => {
+                write!(f, "election timeout (term={})", term.get())
+            }
+            Event::VoteGranted { to, term } => {
+                write!(f, "vote granted (to={to}, term={})", term.get())
+            }
+            Event::VoteDenied { to, term } => {
+                write!(f, "vote denied (to={to}, term={})", term.get())
+            }
+            Event::ProposalDropped {
+                proposal_id,
+                reason,
+            } => write!(
+                f,
+                "proposal dropped (proposal_id={}, reason={reason})",
+                nojson::Json(proposal_id)
+            ),
+            Event::SnapshotNeeded {
+                recent_commands_bytes,
+            } => write!(
+                f,
+                "snapshot needed (recent_commands_bytes={recent_commands_bytes})"
+            ),
+            Event::ProposalCommitted { proposal_id, index } => write!(
+                f,
+                "proposal committed (proposal_id={}, index={})",
+                nojson::Json(proposal_id),
+                index.get()
+            ),
+            Event::DuplicateCommandIgnored { proposal_id, index } => write!(
+                f,
+                "duplicate command ignored (proposal_id={}, index={})",
+                nojson::Json(proposal_id),
+                index.get()
+            ),
         }
     }
 }
 
+/// Version of the storage format written by this crate
+///
+/// It is written as the `"v"` member of snapshots and of `StorageEntry::NodeGeneration`
+/// (the first entry written after each start or load, so every session of a storage file
+/// is covered). Entries without the member are regarded as version 0, which has the same
+/// layout as version 1, so no migration is needed for them.
+pub(crate) const STORAGE_FORMAT_VERSION: u64 = 1;
+
+/// Checks that `entry` was written in a storage format version supported by this crate
+pub(crate) fn check_format_version(entry: &JsonValue) -> Result<(), nojson::JsonParseError> {
+    let Some(version_value) = entry.get().to_member("v").ok().and_then(|m| m.get()) else {
+        // Version 0 (written before the version member was introduced)
+        return Ok(());
+    };
+    let version: u64 = version_value.try_into()?;
+    if version > STORAGE_FORMAT_VERSION {
+        return Err(version_value.invalid(format!(
+            "unsupported storage format version {version} (supported up to {STORAGE_FORMAT_VERSION})"
+        )));
+    }
+    Ok(())
+}
+
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum StorageEntry {
     Term(noraft::Term),
     VotedFor(Option<NodeId>),
     NodeGeneration(u64),
+    /// Marks the start of a fresh log right after a snapshot at `index`
+    ///
+    /// Written by compaction (`FileStorage::save_snapshot()`) so that `load()` can verify
+    /// that the subsequent log entries are contiguous with the snapshot.
+    SnapshotBoundary {
+        index: noraft::LogIndex,
+    },
 }
 
 impl nojson::DisplayJson for StorageEntry {
@@ -408,34 +1116,111 @@ impl nojson::DisplayJson for StorageEntry {
             }),
             StorageEntry::NodeGeneration(generation) => f.object(|f| {
                 f.member("type", "NodeGeneration")?;
-                f.member("generation", generation)
+                f.member("generation", generation)?;
+                f.member("v", STORAGE_FORMAT_VERSION)
+            }),
+            StorageEntry::SnapshotBoundary { index } => f.object(|f| {
+                f.member("type", "SnapshotBoundary")?;
+                f.member("index", index.get())
             }),
         }
     }
 }
 
 impl<'text, 'raw> TryFrom<nojson::RawJsonValue<'text, 'raw>> for StorageEntry {
-    type Error = nojson::JsonParseError;
+    type Error = DecodeError;
 
     fn try_from(value: nojson::RawJsonValue<'text, 'raw>) -> Result<Self, Self::Error> {
-        let ty = value
-            .to_member("type")?
-            .required()?
-            .to_unquoted_string_str()?;
+        let decoder = Decoder::new("StorageEntry", value);
+        let ty = decoder.ty()?;
         match ty.as_ref() {
             "Term" => {
-                let term = value.to_member("term")?.required()?.try_into()?;
+                let term = decoder.member("term")?;
                 Ok(StorageEntry::Term(noraft::Term::new(term)))
             }
             "VotedFor" => {
-                let node_id: Option<u64> = value.to_member("node_id")?.try_into()?;
+                let node_id: Option<u64> = decoder.optional_member("node_id")?;
                 Ok(StorageEntry::VotedFor(node_id.map(NodeId::new)))
             }
             "NodeGeneration" => {
-                let generation = value.to_member("generation")?.required()?.try_into()?;
+                let generation = decoder.member("generation")?;
                 Ok(StorageEntry::NodeGeneration(generation))
             }
-            ty => Err(value.invalid(format!("unknown storage entry type: {ty}"))),
+            "SnapshotBoundary" => {
+                let index = decoder.member("index")?;
+                Ok(StorageEntry::SnapshotBoundary {
+                    index: noraft::LogIndex::new(index),
+                })
+            }
+            ty => Err(decoder.unknown_type(ty)),
         }
     }
 }
+
+/// Helper for decoding JSON objects while recording which type and member failed
+#[derive(Debug, Clone, Copy)]
+struct Decoder<'text, 'raw> {
+    type_name: &'static str,
+    value: nojson::RawJsonValue<'text, 'raw>,
+}
+
+impl<'text, 'raw> Decoder<'text, 'raw> {
+    fn new(type_name: &'static str, value: nojson::RawJsonValue<'text, 'raw>) -> Self {
+        Self { type_name, value }
+    }
+
+    fn error(&self, field: &'static str, source: nojson::JsonParseError) -> DecodeError {
+        DecodeError::new(self.type_name, Some(field), source)
+    }
+
+    fn ty(&self) -> Result<std::borrow::Cow<'text, str>, DecodeError> {
+        self.raw_member("type")?
+            .to_unquoted_string_str()
+            .map_err(|e| self.error("type", e))
+    }
+
+    fn raw_member(
+        &self,
+        name: &'static str,
+    ) -> Result<nojson::RawJsonValue<'text, 'raw>, DecodeError> {
+        self.value
+            .to_member(name)
+            .and_then(|m| m.required())
+            .map_err(|e| self.error(name, e))
+    }
+
+    fn optional_raw_member(
+        &self,
+        name: &'static str,
+    ) -> Result<Option<nojson::RawJsonValue<'text, 'raw>>, DecodeError> {
+        self.value
+            .to_member(name)
+            .map(|m| m.get())
+            .map_err(|e| self.error(name, e))
+    }
+
+    fn member<T>(&self, name: &'static str) -> Result<T, DecodeError>
+    where
+        T: TryFrom<nojson::RawJsonValue<'text, 'raw>, Error = nojson::JsonParseError>,
+    {
+        T::try_from(self.raw_member(name)?).map_err(|e| self.error(name, e))
+    }
+
+    fn optional_member<T>(&self, name: &'static str) -> Result<Option<T>, DecodeError>
+    where
+        T: TryFrom<nojson::RawJsonValue<'text, 'raw>, Error = nojson::JsonParseError>,
+    {
+        self.value
+            .to_member(name)
+            .and_then(|m| m.try_into())
+            .map_err(|e| self.error(name, e))
+    }
+
+    fn unknown_type(&self, ty: &str) -> DecodeError {
+        self.invalid("type", format!("unknown type: {ty}"))
+    }
+
+    fn invalid(&self, field: &'static str, reason: String) -> DecodeError {
+        DecodeError::new(self.type_name, Some(field), self.value.invalid(reason))
+    }
+}
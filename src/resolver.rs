@@ -0,0 +1,110 @@
+use crate::node::NodeId;
+
+/// Mapping from node IDs to network addresses, consulted by transports
+pub trait Resolver: std::fmt::Debug {
+    fn resolve(&self, node_id: NodeId) -> crate::Result<std::net::SocketAddr>;
+}
+
+/// `Resolver` that treats node IDs as localhost port numbers (see `NodeId::to_localhost_addr()`)
+#[derive(Debug, Default, Clone, Copy)]
+pub struct LocalhostResolver;
+
+impl Resolver for LocalhostResolver {
+    fn resolve(&self, node_id: NodeId) -> crate::Result<std::net::SocketAddr> {
+        node_id.to_localhost_addr()
+    }
+}
+
+/// `Resolver` backed by an explicit table of node addresses
+///
+/// The table can be updated at any time (e.g., when a node moves to another host).
+#[derive(Debug, Default, Clone)]
+pub struct StaticResolver {
+    addrs: std::collections::BTreeMap<NodeId, std::net::SocketAddr>,
+}
+
+impl StaticResolver {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Registers (or replaces) the address of `node_id`, returning the previous address if any
+    pub fn register_node(
+        &mut self,
+        node_id: NodeId,
+        addr: std::net::SocketAddr,
+    ) -> Option<std::net::SocketAddr> {
+        self.addrs.insert(node_id, addr)
+    }
+
+    pub fn deregister_node(&mut self, node_id: NodeId) -> Option<std::net::SocketAddr> {
+        self.addrs.remove(&node_id)
+    }
+}
+
+impl Resolver for StaticResolver {
+    fn resolve(&self, node_id: NodeId) -> crate::Result<std::net::SocketAddr> {
+        self.addrs
+            .get(&node_id)
+            .copied()
+            .ok_or_else(|| crate::Error::new(format!("unknown node id: {node_id}")))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug)]
+    struct OffsetResolver {
+        base_port: u16,
+    }
+
+    impl Resolver for OffsetResolver {
+        fn resolve(&self, node_id: NodeId) -> crate::Result<std::net::SocketAddr> {
+            let offset =
+                u16::try_from(node_id.get()).map_err(|e| crate::Error::new(e.to_string()))?;
+            let port = self.base_port + offset;
+            Ok(std::net::SocketAddr::from(([10, 0, 0, 1], port)))
+        }
+    }
+
+    #[test]
+    fn custom_resolver() {
+        let resolver = OffsetResolver { base_port: 7000 };
+        let addr = NodeId::new(3).to_addr(&resolver).expect("resolvable");
+        assert_eq!(addr, "10.0.0.1:7003".parse().expect("valid addr"));
+
+        let dyn_resolver: &dyn Resolver = &resolver;
+        let addr = NodeId::new(4).to_addr(dyn_resolver).expect("resolvable");
+        assert_eq!(addr, "10.0.0.1:7004".parse().expect("valid addr"));
+    }
+
+    #[test]
+    fn localhost_resolver() {
+        let addr = NodeId::new(9000)
+            .to_addr(&LocalhostResolver)
+            .expect("resolvable");
+        assert_eq!(addr, "127.0.0.1:9000".parse().expect("valid addr"));
+        assert!(NodeId::new(1 << 20).to_addr(&LocalhostResolver).is_err());
+    }
+
+    #[test]
+    fn static_resolver() {
+        let mut resolver = StaticResolver::new();
+        let node_id = NodeId::new(1);
+        assert!(node_id.to_addr(&resolver).is_err());
+
+        let old_addr = "192.168.0.1:4000".parse().expect("valid addr");
+        let new_addr = "192.168.0.2:4000".parse().expect("valid addr");
+        assert_eq!(resolver.register_node(node_id, old_addr), None);
+        assert_eq!(node_id.to_addr(&resolver).expect("resolvable"), old_addr);
+
+        // The node moves to another host
+        assert_eq!(resolver.register_node(node_id, new_addr), Some(old_addr));
+        assert_eq!(node_id.to_addr(&resolver).expect("resolvable"), new_addr);
+
+        assert_eq!(resolver.deregister_node(node_id), Some(new_addr));
+        assert!(node_id.to_addr(&resolver).is_err());
+    }
+}
@@ -0,0 +1,52 @@
+use crate::node::{ApplyAction, Node, NodeId, ProposalId};
+
+/// Helper for routing the responses of committed commands back to clients
+///
+/// `ApplyAction::source()` is only visible on the proposing node, so if that node restarts
+/// (or otherwise loses track of the proposal) before the command commits, nobody can reply.
+/// `ResponseRouter` embeds the routing information (e.g., the client address and request ID)
+/// together with the ID of the proposing node into the `source` of the command,
+/// so that any node applying the command can recover it via `ResponseRouter::route()`.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct ResponseRouter;
+
+impl ResponseRouter {
+    /// Same as `Node::propose_command()`, but uses `route` as the routing information of the response
+    pub fn propose_command<R, T>(node: &mut Node, route: R, request: T) -> ProposalId
+    where
+        R: nojson::DisplayJson,
+        T: nojson::DisplayJson,
+    {
+        let source = nojson::object(|f| {
+            f.member("node_id", node.id())?;
+            f.member("route", &route)
+        });
+        node.propose_command(source, request)
+    }
+
+    /// Returns the routing information of a command proposed by `propose_command()`
+    ///
+    /// Unlike `ApplyAction::source()`, this is available on every node applying the command.
+    /// Returns `None` if the command was not proposed via `ResponseRouter`.
+    ///
+    /// Since every node applies the command, an applier must check that `Route::node_id` is
+    /// its own `Node::id()` before answering the client; otherwise every replica would reply.
+    pub fn route(apply: &ApplyAction) -> Option<Route<'_>> {
+        let source = apply.raw_source();
+        let node_id = source.to_member("node_id").ok()?.required().ok()?;
+        let value = source.to_member("route").ok()?.required().ok()?;
+        Some(Route {
+            node_id: node_id.try_into().ok()?,
+            value,
+        })
+    }
+}
+
+/// Routing information recovered by `ResponseRouter::route()`
+#[derive(Debug, Clone, Copy)]
+pub struct Route<'a> {
+    /// Node that proposed the command (i.e., the node the client is connected to)
+    pub node_id: NodeId,
+    /// Routing information passed to `ResponseRouter::propose_command()`
+    pub value: nojson::RawJsonValue<'a, 'a>,
+}
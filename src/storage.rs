@@ -1,23 +1,228 @@
-use crate::node::JsonValue;
+use crate::node::{JsonValue, Node, StorageEntry};
+
+/// How entries are framed in a storage file
+///
+/// The format is not recorded in the file, so a file must be reopened with the format it was
+/// written with.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum StorageFormat {
+    /// One JSON entry per line (human-readable)
+    #[default]
+    JsonLines,
+
+    /// Each JSON entry is preceded by its byte length as a 4-byte big-endian integer
+    ///
+    /// Loading does not need to scan for line breaks, which makes startup cheaper for large logs.
+    LengthPrefixed,
+}
 
 #[derive(Debug)]
 pub struct FileStorage {
     file: std::fs::File,
+    path: std::path::PathBuf,
+    read_only: bool,
+    flusher: Option<BackgroundFlusher>,
+    format: StorageFormat,
 }
 
 impl FileStorage {
+    /// Opens (or creates) a storage file for reading and writing
+    ///
+    /// An exclusive advisory lock is taken on the file so that two handles (e.g., a stale
+    /// process and a restarted one) cannot interleave appends. If the file is already locked,
+    /// this returns an error of kind `WouldBlock`. The lock is released when the storage is dropped.
     pub fn open<P: AsRef<std::path::Path>>(path: P) -> std::io::Result<Self> {
+        let path = path.as_ref();
         let file = std::fs::OpenOptions::new()
             .read(true)
             .write(true)
             .create(true)
             .truncate(false)
             .open(path)?;
-        Ok(Self { file })
+        match file.try_lock() {
+            Ok(()) => {}
+            Err(std::fs::TryLockError::WouldBlock) => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::WouldBlock,
+                    format!("storage already in use: {}", path.display()),
+                ));
+            }
+            Err(std::fs::TryLockError::Error(e)) => return Err(e),
+        }
+        Ok(Self {
+            file,
+            path: path.to_path_buf(),
+            read_only: false,
+            flusher: None,
+            format: StorageFormat::default(),
+        })
+    }
+
+    /// Same as `open()`, but stores entries in the given `format` instead of JSON Lines
+    ///
+    /// For `StorageFormat::LengthPrefixed`, a frame torn by a crash in the middle of an append
+    /// is truncated, so that subsequent appends are not hidden behind it.
+    pub fn open_with_format<P: AsRef<std::path::Path>>(
+        path: P,
+        format: StorageFormat,
+    ) -> std::io::Result<Self> {
+        let mut storage = Self::open(path)?;
+        storage.format = format;
+        if format == StorageFormat::LengthPrefixed {
+            storage.truncate_torn_frame()?;
+        }
+        Ok(storage)
+    }
+
+    fn truncate_torn_frame(&mut self) -> std::io::Result<()> {
+        use std::io::{BufReader, Read, Seek, SeekFrom};
+
+        let file_len = self.file.metadata()?.len();
+        self.file.seek(SeekFrom::Start(0))?;
+        let mut reader = BufReader::new(&mut self.file);
+        let mut valid_len = 0;
+        while valid_len + 4 <= file_len {
+            let mut len = [0; 4];
+            reader.read_exact(&mut len)?;
+            let frame_end = valid_len + 4 + u64::from(u32::from_be_bytes(len));
+            if frame_end > file_len {
+                break;
+            }
+            reader.seek_relative((frame_end - valid_len - 4) as i64)?;
+            valid_len = frame_end;
+        }
+
+        if valid_len < file_len {
+            eprintln!(
+                "Warning: Truncating a torn frame at the end of storage ({} bytes)",
+                file_len - valid_len
+            );
+            self.file.set_len(valid_len)?;
+        }
+        Ok(())
+    }
+
+    /// Same as `open()`, but also spawns a thread that syncs the file to disk every `interval`
+    ///
+    /// Appends only write to the OS page cache, so without explicit `sync()` calls, entries may be
+    /// lost on a power failure. The background thread bounds such loss to about `interval`
+    /// without paying the cost of a sync per append. The thread is stopped (after a final sync)
+    /// when the storage is dropped.
+    pub fn open_with_flush_interval<P: AsRef<std::path::Path>>(
+        path: P,
+        interval: std::time::Duration,
+    ) -> std::io::Result<Self> {
+        Self::open_with_format_and_flush_interval(path, StorageFormat::default(), interval)
+    }
+
+    /// Combination of `open_with_format()` and `open_with_flush_interval()`
+    pub fn open_with_format_and_flush_interval<P: AsRef<std::path::Path>>(
+        path: P,
+        format: StorageFormat,
+        interval: std::time::Duration,
+    ) -> std::io::Result<Self> {
+        let mut storage = Self::open_with_format(path, format)?;
+        let file = storage.file.try_clone()?;
+        storage.flusher = Some(BackgroundFlusher::spawn(file, interval));
+        Ok(storage)
+    }
+
+    /// Syncs the appended entries to disk
+    pub fn sync(&self) -> std::io::Result<()> {
+        self.file.sync_data()
+    }
+
+    /// Opens an existing storage file without write access (e.g., for inspection or backup tools)
+    ///
+    /// Only loading is supported; appending entries or saving a snapshot returns an error.
+    /// No lock is taken, so the file can be inspected while another handle is writing to it.
+    pub fn open_read_only<P: AsRef<std::path::Path>>(path: P) -> std::io::Result<Self> {
+        Self::open_read_only_with_format(path, StorageFormat::default())
+    }
+
+    /// Same as `open_read_only()`, but reads entries stored in the given `format`
+    ///
+    /// Unlike `open_with_format()`, a torn frame is not truncated (it is skipped when loading).
+    pub fn open_read_only_with_format<P: AsRef<std::path::Path>>(
+        path: P,
+        format: StorageFormat,
+    ) -> std::io::Result<Self> {
+        let path = path.as_ref();
+        let file = std::fs::OpenOptions::new().read(true).open(path)?;
+        Ok(Self {
+            file,
+            path: path.to_path_buf(),
+            read_only: true,
+            flusher: None,
+            format,
+        })
+    }
+
+    /// Checks the integrity of the storage file at `path` without loading it into a node
+    ///
+    /// The following problems are detected:
+    /// - lines that are not valid JSON
+    /// - snapshots other than a single one at the beginning of the file
+    /// - snapshots that fail validation (e.g., checksum mismatch)
+    /// - gaps between consecutive `LogEntries`
+    ///
+    /// The file is read as JSON Lines (see `verify_with_format()` for other formats).
+    /// The file is only read (no lock is taken). Scanning continues after a problem is found
+    /// so that the counts in the report cover the whole file.
+    pub fn verify<P: AsRef<std::path::Path>>(path: P) -> std::io::Result<StorageReport> {
+        Self::verify_with_format(path, StorageFormat::default())
+    }
+
+    /// Same as `verify()`, but reads entries stored in the given `format`
+    ///
+    /// For `StorageFormat::LengthPrefixed`, problems are prefixed with their frame number
+    /// instead of their line number.
+    pub fn verify_with_format<P: AsRef<std::path::Path>>(
+        path: P,
+        format: StorageFormat,
+    ) -> std::io::Result<StorageReport> {
+        use std::io::BufRead;
+
+        let file = std::fs::File::open(path)?;
+        let mut reader = std::io::BufReader::new(file);
+        let mut verifier = Verifier::default();
+        match format {
+            StorageFormat::JsonLines => {
+                for (i, line) in reader.lines().enumerate() {
+                    verifier.check_line(i + 1, &line?);
+                }
+            }
+            StorageFormat::LengthPrefixed => {
+                let mut frame_number = 0;
+                while let Some(frame) = read_frame(&mut reader)? {
+                    frame_number += 1;
+                    verifier.check_frame(frame_number, &frame);
+                }
+            }
+        }
+        Ok(verifier.report)
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    pub fn format(&self) -> StorageFormat {
+        self.format
+    }
+
+    fn check_writable(&self) -> std::io::Result<()> {
+        if self.read_only {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::PermissionDenied,
+                "storage is opened in read-only mode",
+            ));
+        }
+        Ok(())
     }
 
     pub fn load_entries(&mut self) -> std::io::Result<Vec<JsonValue>> {
-        use std::io::{BufRead, BufReader, Seek, SeekFrom};
+        use std::io::{BufReader, Seek, SeekFrom};
 
         // Reset file pointer to the beginning
         self.file.seek(SeekFrom::Start(0))?;
@@ -25,33 +230,102 @@ impl FileStorage {
         let reader = BufReader::new(&mut self.file);
         let mut entries = Vec::new();
 
-        for line in reader.lines() {
-            let line = line?;
-            let trimmed = line.trim();
+        for entry in read_entries(self.format, reader) {
+            entries.push(entry?);
+        }
+        self.recover_leading_snapshot(&mut entries)?;
 
-            // Skip empty lines
-            if trimmed.is_empty() {
-                continue;
-            }
+        Ok(entries)
+    }
 
-            // Parse JSON using nojson
-            match nojson::RawJsonOwned::parse(trimmed) {
-                Ok(raw_json) => {
-                    let value = JsonValue::new(raw_json.value());
-                    entries.push(value);
+    /// Replaces a corrupt leading snapshot with the newest valid copy saved by
+    /// `save_snapshot_rotating()` (if any)
+    ///
+    /// If the copy is older than the corrupt snapshot, it does not match the `SnapshotBoundary`
+    /// that follows, so loading fails instead of silently skipping the log in between.
+    fn recover_leading_snapshot(&self, entries: &mut Vec<JsonValue>) -> std::io::Result<()> {
+        let Some(first) = entries.first() else {
+            return Ok(());
+        };
+        let ty: Option<String> = first.get_optional_member("type").ok().flatten();
+        let replace = match ty.as_deref() {
+            Some("InstallSnapshotRpc") => {
+                if Node::parse_snapshot_json(first).is_ok() {
+                    return Ok(());
                 }
-                Err(e) => eprintln!("Warning: Failed to parse JSON line: {}", e),
+                true
             }
+            // The snapshot line itself could not be parsed and has been skipped
+            Some("SnapshotBoundary") => false,
+            _ => return Ok(()),
+        };
+
+        let Some(snapshot) = self.load_latest_snapshot()? else {
+            return Ok(());
+        };
+        eprintln!("Warning: Loading the latest snapshot copy instead of the corrupt snapshot");
+        if replace {
+            entries[0] = snapshot;
+        } else {
+            entries.insert(0, snapshot);
         }
+        Ok(())
+    }
 
-        Ok(entries)
+    /// Restores `node` from the stored entries
+    ///
+    /// This is equivalent to `node.load(&storage.load_entries()?)`, but reports why loading
+    /// failed instead of returning `false`.
+    /// All entries are read before the node is touched, so an I/O error leaves `node` unchanged.
+    /// Returns the user machine of the last snapshot (if any).
+    pub fn replay_into(&mut self, node: &mut Node) -> std::io::Result<Option<JsonValue>> {
+        let entries = self.load_entries()?;
+        node.try_load_iter(entries).map_err(|e| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("failed to load storage entries: {e}"),
+            )
+        })
     }
 
     pub fn append_entry(&mut self, entry: &JsonValue) -> std::io::Result<()> {
-        use std::io::Write;
+        self.append_entry_at(entry)?;
+        Ok(())
+    }
+
+    /// Appends an entry and returns the byte offset at which the entry's line (or frame) starts
+    pub fn append_entry_at(&mut self, entry: &JsonValue) -> std::io::Result<u64> {
+        use std::io::{Seek, Write};
+
+        self.check_writable()?;
+
+        // Always append to the end of the file regardless of the current file pointer
+        let offset = self.file.seek(std::io::SeekFrom::End(0))?;
 
         // Write the entry to the file
-        writeln!(self.file, "{}", entry)?;
+        write_entry(&mut self.file, self.format, entry)?;
+
+        // Ensure data is flushed to disk
+        self.file.flush()?;
+
+        Ok(offset)
+    }
+
+    pub fn append_entries(&mut self, entries: &[JsonValue]) -> std::io::Result<()> {
+        use std::io::{Seek, Write};
+
+        self.check_writable()?;
+        if entries.is_empty() {
+            return Ok(());
+        }
+
+        // Serialize all entries first so that they are written with a single write call
+        let mut buf = Vec::new();
+        for entry in entries {
+            write_entry(&mut buf, self.format, entry)?;
+        }
+        self.file.seek(std::io::SeekFrom::End(0))?;
+        self.file.write_all(&buf)?;
 
         // Ensure data is flushed to disk
         self.file.flush()?;
@@ -62,6 +336,8 @@ impl FileStorage {
     pub fn save_snapshot(&mut self, entry: &JsonValue) -> std::io::Result<()> {
         use std::io::Write;
 
+        self.check_writable()?;
+
         // Truncate the file to clear all existing content
         self.file.set_len(0)?;
 
@@ -69,14 +345,468 @@ impl FileStorage {
         use std::io::Seek;
         self.file.seek(std::io::SeekFrom::Start(0))?;
 
-        // Write the snapshot entry to the file
-        writeln!(self.file, "{}", entry)?;
+        write_snapshot_lines(&mut self.file, self.format, entry)?;
 
         // Ensure data is flushed to disk
         self.file.flush()?;
 
         Ok(())
     }
+
+    /// Replaces the whole storage with `snapshot` atomically
+    ///
+    /// Unlike `save_snapshot()`, which truncates the file in place, the snapshot is first written
+    /// to a temporary file that is then renamed over the storage file, so a crash in the middle
+    /// leaves either the old or the new content. The directory is synced after the rename
+    /// so that the new content survives a crash once this returns.
+    ///
+    /// This is intended for the `Action::SendSnapshot` path: the snapshot created for the follower
+    /// can also be used to compact the local storage, without building it twice.
+    pub fn compact(&mut self, snapshot: &JsonValue) -> std::io::Result<()> {
+        use std::io::Write;
+
+        self.check_writable()?;
+
+        let file_name = self
+            .path
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let tmp_path = self.path.with_file_name(format!("{file_name}.compact"));
+        {
+            let mut tmp = std::fs::File::create(&tmp_path)?;
+            write_snapshot_lines(&mut tmp, self.format, snapshot)?;
+            tmp.flush()?;
+            tmp.sync_all()?;
+        }
+
+        // Lock the new file before it becomes visible so that no other handle can take it over
+        let file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&tmp_path)?;
+        file.lock()?;
+        std::fs::rename(&tmp_path, &self.path)?;
+        self.sync_dir()?;
+
+        let interval = self.flusher.take().map(|flusher| flusher.interval);
+        self.file = file;
+        if let Some(interval) = interval {
+            let file = self.file.try_clone()?;
+            self.flusher = Some(BackgroundFlusher::spawn(file, interval));
+        }
+        Ok(())
+    }
+
+    /// Same as `save_snapshot()`, but also keeps a copy of the snapshot in a separate file
+    ///
+    /// The copies are named `<storage file name>.snapshot.<timestamp>` and placed next to
+    /// the storage file. Only the newest `keep` copies are retained, so that an operator
+    /// (or `load_latest_snapshot()`) can fall back to a previous snapshot, e.g., after a bad upgrade.
+    /// `load_entries()` and `replay_into()` use the newest valid copy if the snapshot in the
+    /// storage file itself is corrupt.
+    pub fn save_snapshot_rotating(
+        &mut self,
+        entry: &JsonValue,
+        keep: usize,
+    ) -> std::io::Result<()> {
+        use std::io::Write;
+
+        self.check_writable()?;
+
+        // Timestamps are forced to be increasing so that the file names reflect the save order
+        let archives = self.snapshot_archives()?;
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_micros() as u64)
+            .unwrap_or(0);
+        let timestamp = archives
+            .last()
+            .map(|(last, _)| now.max(last + 1))
+            .unwrap_or(now);
+
+        let archive_path = self.snapshot_archive_path(timestamp);
+        let mut file = std::fs::File::create(&archive_path)?;
+        writeln!(file, "{}", entry)?;
+        file.sync_all()?;
+
+        let archives = self.snapshot_archives()?;
+        let prune_count = archives.len().saturating_sub(keep.max(1));
+        for (_, path) in &archives[..prune_count] {
+            std::fs::remove_file(path)?;
+        }
+        self.sync_dir()?;
+
+        self.save_snapshot(entry)
+    }
+
+    /// Loads the newest valid snapshot saved by `save_snapshot_rotating()`
+    ///
+    /// Copies that cannot be parsed or fail the snapshot validation (e.g., checksum mismatch)
+    /// are skipped in favor of the previous one.
+    pub fn load_latest_snapshot(&self) -> std::io::Result<Option<JsonValue>> {
+        for (_, path) in self.snapshot_archives()?.into_iter().rev() {
+            let text = std::fs::read_to_string(&path)?;
+            let Some(snapshot) = text.lines().find_map(parse_entry_line) else {
+                continue;
+            };
+            match Node::parse_snapshot_json(&snapshot) {
+                Ok(_) => return Ok(Some(snapshot)),
+                Err(e) => eprintln!(
+                    "Warning: Skipping invalid snapshot {}: {}",
+                    path.display(),
+                    e
+                ),
+            }
+        }
+        Ok(None)
+    }
+
+    fn snapshot_archive_prefix(&self) -> String {
+        let file_name = self
+            .path
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        format!("{file_name}.snapshot.")
+    }
+
+    fn snapshot_archive_path(&self, timestamp: u64) -> std::path::PathBuf {
+        let name = format!("{}{timestamp:020}", self.snapshot_archive_prefix());
+        self.path.with_file_name(name)
+    }
+
+    /// Returns the directory containing the storage file
+    fn dir(&self) -> &std::path::Path {
+        match self.path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => std::path::Path::new("."),
+        }
+    }
+
+    /// Makes the file creations, renames and removals in `dir()` durable
+    fn sync_dir(&self) -> std::io::Result<()> {
+        // Directories cannot be opened as files on some platforms (e.g., Windows)
+        #[cfg(unix)]
+        std::fs::File::open(self.dir())?.sync_all()?;
+        Ok(())
+    }
+
+    /// Returns the snapshot copies sorted from oldest to newest
+    fn snapshot_archives(&self) -> std::io::Result<Vec<(u64, std::path::PathBuf)>> {
+        let prefix = self.snapshot_archive_prefix();
+
+        let mut archives = Vec::new();
+        for dir_entry in std::fs::read_dir(self.dir())? {
+            let path = dir_entry?.path();
+            let Some(name) = path.file_name().and_then(|name| name.to_str()) else {
+                continue;
+            };
+            if let Some(timestamp) = name
+                .strip_prefix(&prefix)
+                .and_then(|suffix| suffix.parse::<u64>().ok())
+            {
+                archives.push((timestamp, path));
+            }
+        }
+        archives.sort();
+        Ok(archives)
+    }
+}
+
+/// Result of `FileStorage::verify()`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct StorageReport {
+    /// Number of non-empty lines (or frames)
+    pub entries: usize,
+    /// Number of snapshot entries
+    pub snapshots: usize,
+    /// Number of raft log entries (including those embedded in the snapshot)
+    pub log_entries: usize,
+    /// Description of the first problem found, prefixed with its line (or frame) number
+    pub first_problem: Option<String>,
+}
+
+impl StorageReport {
+    pub fn is_ok(&self) -> bool {
+        self.first_problem.is_none()
+    }
+}
+
+#[derive(Debug, Default)]
+struct Verifier {
+    report: StorageReport,
+    // Index of the last log entry seen so far (`None` until the log start is known)
+    last_index: Option<u64>,
+}
+
+impl Verifier {
+    fn check_line(&mut self, line_number: usize, line: &str) {
+        if let Err(problem) = self.check_text(line) {
+            self.record_problem(format!("line {line_number}: {problem}"));
+        }
+    }
+
+    fn check_frame(&mut self, frame_number: usize, frame: &[u8]) {
+        let result = match std::str::from_utf8(frame) {
+            Ok(text) => self.check_text(text),
+            Err(e) => {
+                self.report.entries += 1;
+                Err(format!("invalid UTF-8: {e}"))
+            }
+        };
+        if let Err(problem) = result {
+            self.record_problem(format!("frame {frame_number}: {problem}"));
+        }
+    }
+
+    fn check_text(&mut self, text: &str) -> Result<(), String> {
+        let trimmed = text.trim();
+        if trimmed.is_empty() {
+            return Ok(());
+        }
+        self.report.entries += 1;
+
+        nojson::RawJsonOwned::parse(trimmed)
+            .map_err(|e| format!("invalid JSON: {e}"))
+            .and_then(|json| self.check_entry(&JsonValue::from_raw(json)))
+    }
+
+    fn record_problem(&mut self, problem: String) {
+        if self.report.first_problem.is_none() {
+            self.report.first_problem = Some(problem);
+        }
+    }
+
+    fn check_entry(&mut self, entry: &JsonValue) -> Result<(), String> {
+        crate::node_types::check_format_version(entry).map_err(|e| e.to_string())?;
+        let ty: Option<String> = entry
+            .get_optional_member("type")
+            .map_err(|e| e.to_string())?;
+        match ty.as_deref() {
+            Some("InstallSnapshotRpc") => {
+                self.report.snapshots += 1;
+                let is_leading = self.report.entries == 1;
+                let log_entries = entry
+                    .get()
+                    .to_member("log_entries")
+                    .and_then(|m| m.required())
+                    .and_then(|m| m.to_array())
+                    .map(|entries| entries.count())
+                    .unwrap_or(0);
+                self.report.log_entries += log_entries;
+
+                let (position, _) = Node::parse_snapshot_json(entry)
+                    .map_err(|e| format!("invalid snapshot: {e}"))?;
+                self.last_index = Some(position.index.get() + log_entries as u64);
+                if !is_leading {
+                    return Err("snapshot is not at the beginning of the file".to_owned());
+                }
+            }
+            Some("LogEntries") => {
+                let prev_index: u64 = entry.get_member("index").map_err(|e| e.to_string())?;
+                let count = entry
+                    .get()
+                    .to_member("entries")
+                    .and_then(|m| m.required())
+                    .and_then(|m| m.to_array())
+                    .map_err(|e| e.to_string())?
+                    .count();
+                self.report.log_entries += count;
+
+                let last_index = self.last_index.unwrap_or(prev_index);
+                self.last_index = Some(prev_index + count as u64);
+                if prev_index > last_index {
+                    return Err(format!(
+                        "log entries are not contiguous: expected index <= {last_index}, got {prev_index}"
+                    ));
+                }
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+struct BackgroundFlusher {
+    stop_tx: Option<std::sync::mpsc::Sender<()>>,
+    handle: Option<std::thread::JoinHandle<()>>,
+    interval: std::time::Duration,
+}
+
+impl BackgroundFlusher {
+    fn spawn(file: std::fs::File, interval: std::time::Duration) -> Self {
+        let (stop_tx, stop_rx) = std::sync::mpsc::channel::<()>();
+        let handle = std::thread::spawn(move || {
+            loop {
+                let stopped = !matches!(
+                    stop_rx.recv_timeout(interval),
+                    Err(std::sync::mpsc::RecvTimeoutError::Timeout)
+                );
+                if let Err(e) = file.sync_data() {
+                    eprintln!("Warning: Failed to sync storage: {}", e);
+                }
+                if stopped {
+                    break;
+                }
+            }
+        });
+        Self {
+            stop_tx: Some(stop_tx),
+            handle: Some(handle),
+            interval,
+        }
+    }
+}
+
+impl Drop for BackgroundFlusher {
+    fn drop(&mut self) {
+        // Disconnecting the channel wakes up and stops the thread
+        self.stop_tx.take();
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+/// Writes a snapshot entry followed by the boundary marking the start of the post-snapshot log
+fn write_snapshot_lines<W: std::io::Write>(
+    writer: &mut W,
+    format: StorageFormat,
+    entry: &JsonValue,
+) -> std::io::Result<()> {
+    write_entry(writer, format, entry)?;
+
+    // Mark the start of the post-snapshot log so that gaps can be detected on load
+    let index: Result<u64, nojson::JsonParseError> = entry
+        .get()
+        .to_member("position")
+        .and_then(|m| m.required())
+        .and_then(|p| p.to_member("index"))
+        .and_then(|m| m.required())
+        .and_then(|i| i.try_into());
+    if let Ok(index) = index {
+        let boundary = StorageEntry::SnapshotBoundary {
+            index: noraft::LogIndex::new(index),
+        };
+        write_entry(writer, format, &JsonValue::new(boundary))?;
+    }
+    Ok(())
+}
+
+fn write_entry<W: std::io::Write>(
+    writer: &mut W,
+    format: StorageFormat,
+    entry: &JsonValue,
+) -> std::io::Result<()> {
+    match format {
+        StorageFormat::JsonLines => writeln!(writer, "{}", entry),
+        StorageFormat::LengthPrefixed => {
+            let text = entry.to_string();
+            let len = u32::try_from(text.len()).map_err(|_| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    "entry is too large for the length-prefixed format",
+                )
+            })?;
+            writer.write_all(&len.to_be_bytes())?;
+            writer.write_all(text.as_bytes())
+        }
+    }
+}
+
+/// Reads the stored entries in order, skipping malformed ones
+fn read_entries<'a, R: 'a + std::io::BufRead>(
+    format: StorageFormat,
+    mut reader: R,
+) -> Box<dyn 'a + Iterator<Item = std::io::Result<JsonValue>>> {
+    match format {
+        StorageFormat::JsonLines => Box::new(
+            reader
+                .lines()
+                .filter_map(|line| line.map(|line| parse_entry_line(&line)).transpose()),
+        ),
+        StorageFormat::LengthPrefixed => Box::new(std::iter::from_fn(move || {
+            loop {
+                match read_frame(&mut reader) {
+                    Ok(Some(frame)) => {
+                        if let Some(entry) = parse_entry_frame(&frame) {
+                            return Some(Ok(entry));
+                        }
+                    }
+                    Ok(None) => return None,
+                    Err(e) => return Some(Err(e)),
+                }
+            }
+        })),
+    }
+}
+
+/// Reads a length-prefixed frame, returning `None` at the end of the file
+///
+/// A frame truncated by a crash in the middle of an append is treated as the end of the file.
+fn read_frame<R: std::io::Read>(reader: &mut R) -> std::io::Result<Option<Vec<u8>>> {
+    use std::io::Read;
+
+    let mut len = [0; 4];
+    let mut filled = 0;
+    while filled < len.len() {
+        match reader.read(&mut len[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    if filled == 0 {
+        return Ok(None);
+    }
+    if filled < len.len() {
+        eprintln!("Warning: Ignoring truncated frame header at the end of storage");
+        return Ok(None);
+    }
+
+    // The buffer only grows as data is actually read, so a corrupt length cannot cause
+    // a huge allocation
+    let len = u64::from(u32::from_be_bytes(len));
+    let mut frame = Vec::new();
+    reader.take(len).read_to_end(&mut frame)?;
+    if (frame.len() as u64) < len {
+        eprintln!("Warning: Ignoring truncated frame at the end of storage");
+        return Ok(None);
+    }
+    Ok(Some(frame))
+}
+
+/// Parses a stored frame, skipping empty or malformed frames
+fn parse_entry_frame(frame: &[u8]) -> Option<JsonValue> {
+    match std::str::from_utf8(frame) {
+        Ok(text) => parse_entry_line(text),
+        Err(e) => {
+            eprintln!("Warning: Failed to decode frame as UTF-8: {}", e);
+            None
+        }
+    }
+}
+
+/// Parses a stored line, skipping empty or malformed lines
+fn parse_entry_line(line: &str) -> Option<JsonValue> {
+    let trimmed = line.trim();
+
+    // Skip empty lines
+    if trimmed.is_empty() {
+        return None;
+    }
+
+    // Parse JSON using nojson
+    match nojson::RawJsonOwned::parse(trimmed) {
+        Ok(raw_json) => Some(JsonValue::from_raw(raw_json)),
+        Err(e) => {
+            eprintln!("Warning: Failed to parse JSON line: {}", e);
+            None
+        }
+    }
 }
 
 #[cfg(test)]
@@ -94,7 +824,7 @@ mod tests {
 
         // Initialize a single node cluster and save entries
         let mut node = Node::start(NodeId::new(0));
-        assert!(node.init_cluster(&[NodeId::new(0)]));
+        assert!(node.init_cluster(&[NodeId::new(0)]).is_ok());
 
         // Collect storage entries from actions
         let mut entries = Vec::new();
@@ -166,6 +896,248 @@ mod tests {
         assert_eq!(entries.len(), 2, "Should have loaded 2 entries");
     }
 
+    #[test]
+    fn test_file_storage_length_prefixed_round_trip() {
+        let temp_dir = TempDir::new().expect("Failed to create temp directory");
+        let storage_path = temp_dir.path().join("test_storage.bin");
+
+        let entries = [
+            JsonValue::new(StorageEntry::Term(noraft::Term::new(1))),
+            JsonValue::new("first line\nsecond line"),
+            JsonValue::new(nojson::object(|f| f.member("text", "a\n\nb\r\n"))),
+            JsonValue::new(StorageEntry::NodeGeneration(5)),
+        ];
+
+        {
+            let mut storage =
+                FileStorage::open_with_format(&storage_path, StorageFormat::LengthPrefixed)
+                    .expect("Failed to open storage");
+            assert_eq!(storage.format(), StorageFormat::LengthPrefixed);
+            storage
+                .append_entry(&entries[0])
+                .expect("Failed to append entry");
+            storage
+                .append_entries(&entries[1..])
+                .expect("Failed to append entries");
+        }
+
+        // Each entry is framed by its length rather than terminated by a newline
+        let bytes = fs::read(&storage_path).expect("Failed to read file");
+        let first_len = entries[0].to_string().len();
+        assert_eq!(&bytes[..4], &(first_len as u32).to_be_bytes());
+        assert_eq!(&bytes[4..4 + first_len], entries[0].to_string().as_bytes());
+
+        let mut storage =
+            FileStorage::open_with_format(&storage_path, StorageFormat::LengthPrefixed)
+                .expect("Failed to open storage");
+        let loaded = storage.load_entries().expect("Failed to load entries");
+        assert_eq!(loaded, entries);
+
+        // A frame torn by a crash is ignored
+        drop(storage);
+        let mut file = fs::OpenOptions::new()
+            .append(true)
+            .open(&storage_path)
+            .expect("Failed to open file");
+        std::io::Write::write_all(&mut file, &[0, 0, 0, 10, b'{']).expect("Failed to write");
+        drop(file);
+        let mut storage =
+            FileStorage::open_with_format(&storage_path, StorageFormat::LengthPrefixed)
+                .expect("Failed to open storage");
+        let loaded = storage.load_entries().expect("Failed to load entries");
+        assert_eq!(loaded, entries);
+
+        // The torn frame is truncated on open, so entries appended afterwards are loaded
+        let appended = JsonValue::new(StorageEntry::NodeGeneration(6));
+        storage
+            .append_entry(&appended)
+            .expect("Failed to append entry");
+        drop(storage);
+        let mut storage =
+            FileStorage::open_with_format(&storage_path, StorageFormat::LengthPrefixed)
+                .expect("Failed to open storage");
+        let loaded = storage.load_entries().expect("Failed to load entries");
+        assert_eq!(loaded[..entries.len()], entries);
+        assert_eq!(loaded[entries.len()..], [appended]);
+
+        // A torn header is truncated as well
+        drop(storage);
+        let len_before = fs::metadata(&storage_path).expect("metadata").len();
+        let mut file = fs::OpenOptions::new()
+            .append(true)
+            .open(&storage_path)
+            .expect("Failed to open file");
+        std::io::Write::write_all(&mut file, &[0, 0]).expect("Failed to write");
+        drop(file);
+        FileStorage::open_with_format(&storage_path, StorageFormat::LengthPrefixed)
+            .expect("Failed to open storage");
+        let len_after = fs::metadata(&storage_path).expect("metadata").len();
+        assert_eq!(len_after, len_before);
+    }
+
+    #[test]
+    fn test_file_storage_append_entries_matches_sequential_appends() {
+        let temp_dir = TempDir::new().expect("Failed to create temp directory");
+        let sequential_path = temp_dir.path().join("sequential.jsonl");
+        let batched_path = temp_dir.path().join("batched.jsonl");
+
+        let entries = [
+            JsonValue::new(StorageEntry::Term(noraft::Term::new(1))),
+            JsonValue::new(StorageEntry::VotedFor(Some(NodeId::new(0)))),
+            JsonValue::new(StorageEntry::NodeGeneration(3)),
+        ];
+
+        {
+            let mut storage = FileStorage::open(&sequential_path).expect("Failed to open storage");
+            for entry in &entries {
+                storage.append_entry(entry).expect("Failed to append entry");
+            }
+        }
+        {
+            let mut storage = FileStorage::open(&batched_path).expect("Failed to open storage");
+            storage
+                .append_entries(&entries)
+                .expect("Failed to append entries");
+        }
+
+        let sequential = fs::read_to_string(&sequential_path).expect("Failed to read file");
+        let batched = fs::read_to_string(&batched_path).expect("Failed to read file");
+        assert_eq!(sequential, batched);
+    }
+
+    #[test]
+    fn test_file_storage_append_entry_at_returns_offsets() {
+        use std::io::{BufRead, Seek};
+
+        let temp_dir = TempDir::new().expect("Failed to create temp directory");
+        let storage_path = temp_dir.path().join("offsets.jsonl");
+
+        let entries = [
+            JsonValue::new(StorageEntry::Term(noraft::Term::new(1))),
+            JsonValue::new(StorageEntry::VotedFor(None)),
+            JsonValue::new(StorageEntry::NodeGeneration(12)),
+        ];
+
+        let mut offsets = Vec::new();
+        {
+            let mut storage = FileStorage::open(&storage_path).expect("Failed to open storage");
+            offsets.push(storage.append_entry_at(&entries[0]).expect("append"));
+        }
+        {
+            // Reopening must not overwrite the existing entries
+            let mut storage = FileStorage::open(&storage_path).expect("Failed to open storage");
+            offsets.push(storage.append_entry_at(&entries[1]).expect("append"));
+            offsets.push(storage.append_entry_at(&entries[2]).expect("append"));
+        }
+        assert_eq!(offsets[0], 0);
+        assert!(offsets.windows(2).all(|w| w[0] < w[1]));
+
+        let mut file = fs::File::open(&storage_path).expect("Failed to open file");
+        for (offset, entry) in offsets.iter().zip(entries.iter()) {
+            file.seek(std::io::SeekFrom::Start(*offset)).expect("seek");
+            let mut line = String::new();
+            std::io::BufReader::new(&mut file)
+                .read_line(&mut line)
+                .expect("read");
+            assert_eq!(line.trim_end(), entry.get().as_raw_str());
+        }
+    }
+
+    #[test]
+    fn test_file_storage_read_only() {
+        let temp_dir = TempDir::new().expect("Failed to create temp directory");
+        let storage_path = temp_dir.path().join("read_only.jsonl");
+
+        let entry = JsonValue::new(StorageEntry::Term(noraft::Term::new(1)));
+        {
+            let mut storage = FileStorage::open(&storage_path).expect("Failed to open storage");
+            storage
+                .append_entry(&entry)
+                .expect("Failed to append entry");
+        }
+
+        let mut storage =
+            FileStorage::open_read_only(&storage_path).expect("Failed to open storage");
+        assert!(storage.is_read_only());
+
+        let entries = storage.load_entries().expect("Failed to load entries");
+        assert_eq!(entries.len(), 1);
+
+        let err = storage
+            .append_entry(&entry)
+            .expect_err("append should fail");
+        assert_eq!(err.kind(), std::io::ErrorKind::PermissionDenied);
+        let err = storage
+            .append_entries(std::slice::from_ref(&entry))
+            .expect_err("append should fail");
+        assert_eq!(err.kind(), std::io::ErrorKind::PermissionDenied);
+        let err = storage
+            .save_snapshot(&entry)
+            .expect_err("snapshot should fail");
+        assert_eq!(err.kind(), std::io::ErrorKind::PermissionDenied);
+
+        // The file content is left untouched
+        let entries = storage.load_entries().expect("Failed to load entries");
+        assert_eq!(entries.len(), 1);
+    }
+
+    #[test]
+    fn test_file_storage_read_only_length_prefixed() {
+        let temp_dir = TempDir::new().expect("Failed to create temp directory");
+        let storage_path = temp_dir.path().join("read_only.bin");
+
+        let entries = [
+            JsonValue::new(StorageEntry::Term(noraft::Term::new(1))),
+            JsonValue::new(StorageEntry::NodeGeneration(2)),
+        ];
+        let interval = std::time::Duration::from_millis(10);
+        let mut storage = FileStorage::open_with_format_and_flush_interval(
+            &storage_path,
+            StorageFormat::LengthPrefixed,
+            interval,
+        )
+        .expect("Failed to open storage");
+        assert_eq!(storage.format(), StorageFormat::LengthPrefixed);
+        storage
+            .append_entries(&entries)
+            .expect("Failed to append entries");
+
+        // Readable while the writer holds the lock
+        let mut reader =
+            FileStorage::open_read_only_with_format(&storage_path, StorageFormat::LengthPrefixed)
+                .expect("Failed to open storage");
+        assert!(reader.is_read_only());
+        let loaded = reader.load_entries().expect("Failed to load entries");
+        assert_eq!(loaded, entries);
+    }
+
+    #[test]
+    fn test_file_storage_exclusive_lock() {
+        let temp_dir = TempDir::new().expect("Failed to create temp directory");
+        let storage_path = temp_dir.path().join("locked.jsonl");
+
+        let storage = FileStorage::open(&storage_path).expect("Failed to open storage");
+        let err = FileStorage::open(&storage_path).expect_err("storage should be locked");
+        assert_eq!(err.kind(), std::io::ErrorKind::WouldBlock);
+        assert!(err.to_string().contains("storage already in use"));
+
+        // Read-only handles do not conflict with the writer
+        FileStorage::open_read_only(&storage_path).expect("Failed to open storage");
+
+        // The lock is released on drop
+        drop(storage);
+        FileStorage::open(&storage_path).expect("Failed to open storage");
+    }
+
+    #[test]
+    fn test_file_storage_read_only_missing_file() {
+        let temp_dir = TempDir::new().expect("Failed to create temp directory");
+        let storage_path = temp_dir.path().join("missing.jsonl");
+
+        let err = FileStorage::open_read_only(&storage_path).expect_err("file does not exist");
+        assert_eq!(err.kind(), std::io::ErrorKind::NotFound);
+    }
+
     #[test]
     fn test_file_storage_snapshot() {
         let temp_dir = TempDir::new().expect("Failed to create temp directory");
@@ -209,4 +1181,468 @@ mod tests {
             assert_eq!(entries[0].get().as_raw_str(), entry3.get().as_raw_str());
         }
     }
+
+    #[test]
+    fn test_file_storage_snapshot_writes_boundary() {
+        let temp_dir = TempDir::new().expect("Failed to create temp directory");
+        let storage_path = temp_dir.path().join("boundary_storage.jsonl");
+
+        let mut node = Node::start(NodeId::new(0));
+        assert!(node.init_cluster(&[NodeId::new(0)]).is_ok());
+        while node.next_action().is_some() {}
+        let snapshot = node
+            .create_snapshot(node.applied_index(), &"machine")
+            .expect("snapshot should be created");
+
+        let mut storage = FileStorage::open(&storage_path).expect("Failed to open storage");
+        storage
+            .save_snapshot(&snapshot)
+            .expect("Failed to save snapshot");
+
+        let entries = storage.load_entries().expect("Failed to load entries");
+        assert_eq!(entries.len(), 2);
+        let boundary = JsonValue::new(StorageEntry::SnapshotBoundary {
+            index: node.applied_index(),
+        });
+        assert_eq!(entries[1].get().as_raw_str(), boundary.get().as_raw_str());
+
+        let mut restarted = Node::start(NodeId::new(0));
+        let (ok, _) = restarted.load(&entries);
+        assert!(ok);
+    }
+
+    #[test]
+    fn test_file_storage_snapshot_rotation_falls_back_to_previous() {
+        let temp_dir = TempDir::new().expect("Failed to create temp directory");
+        let storage_path = temp_dir.path().join("rotating.jsonl");
+
+        let mut node = Node::start(NodeId::new(0));
+        assert!(node.init_cluster(&[NodeId::new(0)]).is_ok());
+        while node.next_action().is_some() {}
+
+        let mut storage = FileStorage::open(&storage_path).expect("Failed to open storage");
+        for machine in ["v1", "v2", "v3"] {
+            let snapshot = node
+                .create_snapshot(node.applied_index(), &machine)
+                .expect("snapshot should be created");
+            storage
+                .save_snapshot_rotating(&snapshot, 2)
+                .expect("Failed to save snapshot");
+        }
+
+        let archives = storage
+            .snapshot_archives()
+            .expect("Failed to list snapshots");
+        assert_eq!(archives.len(), 2);
+
+        let user_machine = |snapshot: &JsonValue| {
+            let machine: String = snapshot
+                .get()
+                .to_member("user_machine")
+                .and_then(|m| m.required())
+                .and_then(|m| m.try_into())
+                .expect("user_machine should be a string");
+            machine
+        };
+        let latest = storage
+            .load_latest_snapshot()
+            .expect("Failed to load snapshot")
+            .expect("snapshot should exist");
+        assert_eq!(user_machine(&latest), "v3");
+
+        // A corrupt snapshot in the storage file is replaced with the newest copy
+        let text = fs::read_to_string(&storage_path).expect("Failed to read storage");
+        fs::write(&storage_path, text.replacen("v3", "v4", 1)).expect("Failed to corrupt storage");
+        let mut restarted = Node::start(NodeId::new(0));
+        let machine = storage
+            .replay_into(&mut restarted)
+            .expect("Failed to replay entries")
+            .expect("snapshot should exist");
+        assert_eq!(machine.get().as_raw_str(), r#""v3""#);
+        assert_eq!(restarted.applied_index(), node.applied_index());
+
+        // Corrupt the newest copy
+        let (_, newest_path) = archives.last().expect("snapshot should exist");
+        let text = fs::read_to_string(newest_path).expect("Failed to read snapshot");
+        fs::write(newest_path, text.replace("v3", "v4")).expect("Failed to corrupt snapshot");
+
+        let latest = storage
+            .load_latest_snapshot()
+            .expect("Failed to load snapshot")
+            .expect("snapshot should exist");
+        assert_eq!(user_machine(&latest), "v2");
+    }
+
+    fn write_verify_fixture(temp_dir: &TempDir, name: &str) -> (std::path::PathBuf, JsonValue) {
+        write_verify_fixture_with_format(temp_dir, name, StorageFormat::JsonLines)
+    }
+
+    fn write_verify_fixture_with_format(
+        temp_dir: &TempDir,
+        name: &str,
+        format: StorageFormat,
+    ) -> (std::path::PathBuf, JsonValue) {
+        let path = temp_dir.path().join(name);
+        let mut node = Node::start(NodeId::new(0));
+        assert!(node.init_cluster(&[NodeId::new(0)]).is_ok());
+        while node.next_action().is_some() {}
+        let snapshot = node
+            .create_snapshot(node.applied_index(), &"machine")
+            .expect("snapshot should be created");
+
+        let mut storage =
+            FileStorage::open_with_format(&path, format).expect("Failed to open storage");
+        storage
+            .save_snapshot(&snapshot)
+            .expect("Failed to save snapshot");
+        node.propose_command("client", "x");
+        while let Some(action) = node.next_action() {
+            if let crate::node::Action::AppendStorageEntry(entry) = action {
+                storage
+                    .append_entry(&entry)
+                    .expect("Failed to append entry");
+            }
+        }
+        (path, snapshot)
+    }
+
+    #[test]
+    fn test_file_storage_verify_clean_file() {
+        let temp_dir = TempDir::new().expect("Failed to create temp directory");
+        let (path, _) = write_verify_fixture(&temp_dir, "clean.jsonl");
+
+        let report = FileStorage::verify(&path).expect("Failed to verify storage");
+        assert!(report.is_ok(), "{:?}", report.first_problem);
+        assert_eq!(report.snapshots, 1);
+        assert!(report.log_entries > 0);
+        assert_eq!(
+            report.entries,
+            fs::read_to_string(&path).expect("read").lines().count()
+        );
+    }
+
+    #[test]
+    fn test_file_storage_verify_length_prefixed() {
+        let temp_dir = TempDir::new().expect("Failed to create temp directory");
+        let (path, _) =
+            write_verify_fixture_with_format(&temp_dir, "clean.bin", StorageFormat::LengthPrefixed);
+
+        let report = FileStorage::verify_with_format(&path, StorageFormat::LengthPrefixed)
+            .expect("Failed to verify storage");
+        assert!(report.is_ok(), "{:?}", report.first_problem);
+        assert_eq!(report.snapshots, 1);
+        assert!(report.log_entries > 0);
+
+        // Corrupt the first frame without changing its length
+        let mut bytes = fs::read(&path).expect("Failed to read storage");
+        let start = 4 + bytes[4..]
+            .windows(b"machine".len())
+            .position(|w| w == b"machine")
+            .expect("snapshot machine");
+        bytes[start..start + b"machine".len()].copy_from_slice(b"tamperd");
+        fs::write(&path, bytes).expect("Failed to corrupt storage");
+
+        let report = FileStorage::verify_with_format(&path, StorageFormat::LengthPrefixed)
+            .expect("Failed to verify storage");
+        let problem = report
+            .first_problem
+            .expect("checksum mismatch should be detected");
+        assert!(problem.starts_with("frame 1:"), "{problem}");
+        assert!(problem.contains("checksum"), "{problem}");
+    }
+
+    #[test]
+    fn test_file_storage_verify_detects_gap() {
+        let temp_dir = TempDir::new().expect("Failed to create temp directory");
+        let path = temp_dir.path().join("gap.jsonl");
+        let lines = [
+            r#"{"type":"LogEntries","term":0,"index":0,"entries":[{"type":"Term","term":1}]}"#,
+            r#"{"type":"LogEntries","term":1,"index":1,"entries":[{"type":"Term","term":1}]}"#,
+            r#"{"type":"LogEntries","term":1,"index":5,"entries":[{"type":"Term","term":1}]}"#,
+        ];
+        fs::write(&path, lines.join("\n")).expect("Failed to write storage");
+
+        let report = FileStorage::verify(&path).expect("Failed to verify storage");
+        assert_eq!(report.entries, 3);
+        assert_eq!(report.log_entries, 3);
+        let problem = report.first_problem.expect("gap should be detected");
+        assert!(problem.starts_with("line 3:"), "{problem}");
+        assert!(problem.contains("not contiguous"), "{problem}");
+    }
+
+    #[test]
+    fn test_file_storage_verify_detects_bad_checksum() {
+        let temp_dir = TempDir::new().expect("Failed to create temp directory");
+        let (path, _) = write_verify_fixture(&temp_dir, "checksum.jsonl");
+        let text = fs::read_to_string(&path).expect("Failed to read storage");
+        fs::write(&path, text.replacen(r#""machine""#, r#""tampered""#, 1))
+            .expect("Failed to corrupt storage");
+
+        let report = FileStorage::verify(&path).expect("Failed to verify storage");
+        let problem = report
+            .first_problem
+            .expect("checksum mismatch should be detected");
+        assert!(problem.starts_with("line 1:"), "{problem}");
+        assert!(problem.contains("checksum"), "{problem}");
+    }
+
+    #[test]
+    fn test_file_storage_verify_detects_stray_snapshot() {
+        let temp_dir = TempDir::new().expect("Failed to create temp directory");
+        let (path, snapshot) = write_verify_fixture(&temp_dir, "stray.jsonl");
+        let mut storage = FileStorage::open(&path).expect("Failed to open storage");
+        storage
+            .append_entry(&snapshot)
+            .expect("Failed to append entry");
+        let line_count = fs::read_to_string(&path).expect("read").lines().count();
+
+        let report = FileStorage::verify(&path).expect("Failed to verify storage");
+        assert_eq!(report.snapshots, 2);
+        let problem = report
+            .first_problem
+            .expect("stray snapshot should be detected");
+        assert!(
+            problem.starts_with(&format!("line {line_count}:")),
+            "{problem}"
+        );
+        assert!(problem.contains("not at the beginning"), "{problem}");
+    }
+
+    #[test]
+    fn test_file_storage_compact_with_sent_snapshot() {
+        let temp_dir = TempDir::new().expect("Failed to create temp directory");
+        let storage_path = temp_dir.path().join("compact.jsonl");
+
+        let mut leader = Node::start(NodeId::new(0));
+        assert!(leader.init_cluster(&[NodeId::new(0)]).is_ok());
+        leader.propose_command("client", "a");
+        leader.propose_command("client", "b");
+
+        let mut storage = FileStorage::open(&storage_path).expect("Failed to open storage");
+        while let Some(action) = leader.next_action() {
+            if let crate::node::Action::AppendStorageEntry(entry) = action {
+                storage
+                    .append_entry(&entry)
+                    .expect("Failed to append entry");
+            }
+        }
+        let entries_before = storage
+            .load_entries()
+            .expect("Failed to load entries")
+            .len();
+
+        // The snapshot created for `Action::SendSnapshot` also compacts the local storage
+        let snapshot = leader
+            .create_snapshot(leader.applied_index(), &"machine")
+            .expect("snapshot should be created");
+        storage
+            .compact(&snapshot)
+            .expect("Failed to compact storage");
+
+        let mut follower = Node::start(NodeId::new(1));
+        let (ok, _) = follower.load(std::slice::from_ref(&snapshot));
+        assert!(ok);
+
+        let entries = storage.load_entries().expect("Failed to load entries");
+        assert!(entries.len() < entries_before);
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].get().as_raw_str(), snapshot.get().as_raw_str());
+        assert!(!temp_dir.path().join("compact.jsonl.compact").exists());
+
+        // Appends go to the compacted file, and the lock is still held
+        let generation = JsonValue::new(StorageEntry::NodeGeneration(7));
+        storage
+            .append_entry(&generation)
+            .expect("Failed to append entry");
+        assert!(FileStorage::open(&storage_path).is_err());
+        drop(storage);
+
+        let mut storage = FileStorage::open(&storage_path).expect("Failed to open storage");
+        let entries = storage.load_entries().expect("Failed to load entries");
+        assert_eq!(entries.len(), 3);
+        let mut restarted = Node::start(NodeId::new(0));
+        let (ok, user_machine) = restarted.load(&entries);
+        assert!(ok);
+        assert_eq!(
+            user_machine.map(|m| m.as_raw_str().to_owned()),
+            Some(r#""machine""#.to_owned())
+        );
+        assert_eq!(restarted.applied_index(), leader.applied_index());
+    }
+
+    #[test]
+    fn test_file_storage_format_version() {
+        let temp_dir = TempDir::new().expect("Failed to create temp directory");
+
+        // Files written before the version member was introduced are loaded as version 0
+        let v0_path = temp_dir.path().join("v0.jsonl");
+        let v0_lines = [
+            r#"{"type":"NodeGeneration","generation":3}"#,
+            r#"{"type":"Term","term":1}"#,
+            r#"{"type":"LogEntries","term":0,"index":0,"entries":[{"type":"Term","term":1},{"type":"ClusterConfig","voters":[0],"new_voters":[]}]}"#,
+        ];
+        fs::write(&v0_path, v0_lines.join("\n")).expect("Failed to write storage");
+        let mut storage = FileStorage::open(&v0_path).expect("Failed to open storage");
+        let mut node = Node::start(NodeId::new(0));
+        storage.replay_into(&mut node).expect("v0 file should load");
+        assert_eq!(node.generation(), 4);
+        assert!(FileStorage::verify(&v0_path).expect("verify").is_ok());
+
+        // Newly written entries carry the current version
+        let entry = JsonValue::new(StorageEntry::NodeGeneration(4));
+        assert!(entry.to_string().contains(r#""v":1"#));
+
+        // Unknown future versions are rejected with a descriptive error
+        let future_path = temp_dir.path().join("future.jsonl");
+        fs::write(
+            &future_path,
+            r#"{"type":"NodeGeneration","generation":3,"v":99}"#,
+        )
+        .expect("Failed to write storage");
+        let mut storage = FileStorage::open(&future_path).expect("Failed to open storage");
+        let mut node = Node::start(NodeId::new(0));
+        let err = storage
+            .replay_into(&mut node)
+            .expect_err("future version should be rejected");
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+        assert!(
+            err.to_string()
+                .contains("unsupported storage format version 99"),
+            "{err}"
+        );
+
+        let report = FileStorage::verify(&future_path).expect("verify");
+        let problem = report.first_problem.expect("version should be reported");
+        assert!(
+            problem.contains("unsupported storage format version"),
+            "{problem}"
+        );
+    }
+
+    #[test]
+    fn test_file_storage_background_flush() {
+        let temp_dir = TempDir::new().expect("Failed to create temp directory");
+        let storage_path = temp_dir.path().join("flushed.jsonl");
+
+        let interval = std::time::Duration::from_millis(10);
+        let mut storage = FileStorage::open_with_flush_interval(&storage_path, interval)
+            .expect("Failed to open storage");
+        let entries = [
+            JsonValue::new(StorageEntry::NodeGeneration(1)),
+            JsonValue::new(StorageEntry::NodeGeneration(2)),
+        ];
+        storage
+            .append_entry(&entries[0])
+            .expect("Failed to append entry");
+
+        // Let the flusher run while entries are appended
+        std::thread::sleep(interval * 3);
+        storage
+            .append_entry(&entries[1])
+            .expect("Failed to append entry");
+
+        // Compaction swaps the file, so the flusher must follow the new one
+        let mut node = Node::start(NodeId::new(0));
+        assert!(node.init_cluster(&[NodeId::new(0)]).is_ok());
+        while node.next_action().is_some() {}
+        let snapshot = node
+            .create_snapshot(node.applied_index(), &"machine")
+            .expect("snapshot should be created");
+        storage.compact(&snapshot).expect("Failed to compact");
+        assert!(storage.flusher.is_some());
+        storage
+            .append_entry(&entries[1])
+            .expect("Failed to append entry");
+
+        // Dropping the storage performs a final sync, stops the flusher thread, and releases
+        // the lock, so the entries can be loaded by a new handle
+        drop(storage);
+        let mut reopened = FileStorage::open(&storage_path).expect("Failed to reopen storage");
+        let loaded = reopened.load_entries().expect("Failed to load entries");
+        assert_eq!(loaded.len(), 3);
+        assert_eq!(loaded[0], snapshot);
+        assert_eq!(loaded[2], entries[1]);
+
+        let mut restarted = Node::start(NodeId::new(0));
+        assert!(restarted.load(&loaded).0);
+        assert_eq!(restarted.generation(), 3);
+    }
+
+    #[test]
+    fn test_file_storage_replay_into_matches_load() {
+        let temp_dir = TempDir::new().expect("Failed to create temp directory");
+        let storage_path = temp_dir.path().join("replay_storage.jsonl");
+
+        fn persist(node: &mut Node, storage: &mut FileStorage) {
+            while let Some(action) = node.next_action() {
+                if let crate::node::Action::AppendStorageEntry(entry) = action {
+                    storage
+                        .append_entry(&entry)
+                        .expect("Failed to append entry");
+                }
+            }
+        }
+
+        let mut storage = FileStorage::open(&storage_path).expect("Failed to open storage");
+        let mut node = Node::start(NodeId::new(0));
+        assert!(node.init_cluster(&[NodeId::new(0)]).is_ok());
+        node.propose_command(NodeId::new(100), "first");
+        persist(&mut node, &mut storage);
+
+        let snapshot = node
+            .create_snapshot(node.applied_index(), &"machine")
+            .expect("snapshot should be created");
+        storage
+            .save_snapshot(&snapshot)
+            .expect("Failed to save snapshot");
+        node.propose_command(NodeId::new(100), "second");
+        persist(&mut node, &mut storage);
+
+        let entries = storage.load_entries().expect("Failed to load entries");
+        let mut loaded = Node::start(NodeId::new(0));
+        let (ok, loaded_machine) = loaded.load(&entries);
+        assert!(ok);
+
+        let mut replayed = Node::start(NodeId::new(0));
+        let replayed_machine = storage
+            .replay_into(&mut replayed)
+            .expect("Failed to replay entries");
+
+        assert_eq!(
+            replayed_machine.as_ref().map(|m| m.get().as_raw_str()),
+            loaded_machine.map(|m| m.as_raw_str())
+        );
+        assert_eq!(replayed.current_term(), loaded.current_term());
+        assert_eq!(replayed.applied_index(), loaded.applied_index());
+        assert_eq!(replayed.generation(), loaded.generation());
+        assert_eq!(
+            replayed.members().collect::<Vec<_>>(),
+            loaded.members().collect::<Vec<_>>()
+        );
+        assert_eq!(
+            replayed.recent_commands().keys().collect::<Vec<_>>(),
+            loaded.recent_commands().keys().collect::<Vec<_>>()
+        );
+        assert!(!replayed.recent_commands().is_empty());
+    }
+
+    #[test]
+    fn test_file_storage_replay_into_leaves_node_untouched_on_io_error() {
+        let temp_dir = TempDir::new().expect("Failed to create temp directory");
+        let storage_path = temp_dir.path().join("broken_storage.jsonl");
+
+        // A valid entry followed by a line that is not valid UTF-8
+        let mut content = br#"{"type":"NodeGeneration","generation":3}"#.to_vec();
+        content.extend_from_slice(b"\n\xff\xfe\n");
+        fs::write(&storage_path, content).expect("Failed to write storage");
+
+        let mut storage = FileStorage::open(&storage_path).expect("Failed to open storage");
+        let mut node = Node::start(NodeId::new(0));
+        let generation = node.generation();
+        let err = storage
+            .replay_into(&mut node)
+            .expect_err("invalid UTF-8 should be reported");
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+        assert_eq!(node.generation(), generation);
+    }
 }